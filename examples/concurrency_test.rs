@@ -1,5 +1,5 @@
 use polysite::*;
-use std::{thread, time};
+use std::time;
 
 #[derive(Clone)]
 struct Wait(u64);
@@ -7,7 +7,7 @@ impl Compiler for Wait {
     fn next_step(&mut self, ctx: Context) -> CompilerReturn {
         let sec = self.0;
         compile!({
-            thread::sleep(time::Duration::from_secs(sec));
+            tokio::time::sleep(time::Duration::from_secs(sec)).await;
             Ok(CompileStep::Completed(ctx))
         })
     }
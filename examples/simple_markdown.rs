@@ -1,5 +1,5 @@
 use polysite::{
-    compiler::{markdown::MarkdownCompiler, metadata::SetMetadata, template::TemplateEngine},
+    compiler::{markdown::MarkdownCompiler, template::TemplateEngine},
     *,
 };
 
@@ -7,13 +7,7 @@ use polysite::{
 async fn main() {
     simple_logger::SimpleLogger::new().env().init().unwrap();
     let template_engine = TemplateEngine::new("templates/**").unwrap();
-    Builder::new(Config::default())
-        .add_step([Rule::new(
-            "metadata",
-            SetMetadata::new()
-                .global("site_title", "Hello, polysite!")
-                .unwrap(),
-        )])
+    Builder::new(Config::default().set_title("Hello, polysite!"))
         .add_step([
             Rule::new(
                 "posts",
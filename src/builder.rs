@@ -1,5 +1,10 @@
+pub(crate) mod barrier;
+#[allow(clippy::module_inception)]
 pub mod builder;
+pub mod collection;
 pub mod compile;
 pub mod context;
 pub mod metadata;
+pub mod plan;
+pub mod report;
 pub mod rule;
@@ -0,0 +1,317 @@
+use crate::*;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tracing_error::SpanTrace;
+
+#[derive(Debug, Default)]
+struct BarrierEntry {
+    /// Number of tasks which may arrive at the barrier
+    expected: usize,
+    /// Number of tasks waiting at the barrier
+    arrived: usize,
+    released: bool,
+}
+
+#[derive(Debug, Default)]
+struct BarrierState {
+    /// Number of tasks in the build step which are not waiting at a barrier, nor finished
+    running: usize,
+    /// Number of running tasks which are blocked at a
+    /// [`WaitStage`][crate::compiler::utils::WaitStage] until other tasks of the rule progress
+    stage_waiting: usize,
+    barriers: BTreeMap<String, BarrierEntry>,
+    deadlock: Option<String>,
+}
+
+impl BarrierState {
+    /// Release the barrier if all expected tasks arrived
+    fn try_release(&mut self, name: &str) -> bool {
+        let entry = self.barriers.get_mut(name).unwrap();
+        if entry.released || entry.arrived < entry.expected {
+            return false;
+        }
+        entry.released = true;
+        self.running += entry.arrived;
+        true
+    }
+    /// Detect deadlock, which is that no task is running except tasks blocked at `WaitStage`s, but
+    /// some tasks are waiting at barriers
+    fn detect_deadlock(&mut self) -> bool {
+        if self.deadlock.is_some() {
+            return true;
+        }
+        if self.running > self.stage_waiting {
+            return false;
+        }
+        let waiting: Vec<_> = self
+            .barriers
+            .iter()
+            .filter(|(_, e)| !e.released && e.arrived > 0)
+            .map(|(name, e)| format!("`{}` ({} of {} tasks arrived)", name, e.arrived, e.expected))
+            .collect();
+        if waiting.is_empty() {
+            return false;
+        }
+        let mut message = waiting.join(", ");
+        if self.stage_waiting > 0 {
+            message += &format!(", {} tasks at `WaitStage`", self.stage_waiting);
+        }
+        self.deadlock = Some(message);
+        true
+    }
+    fn deadlock_error(&self) -> Error {
+        Error::Deadlock {
+            trace: SpanTrace::capture(),
+            message: self.deadlock.clone().unwrap_or_default(),
+        }
+    }
+}
+
+/// Named barriers shared by the rules in one build step.
+///
+/// All tasks of the rules whose compilers declare the barrier by [`Compiler::barriers`] take
+/// part in it. Tasks which finish without arriving leave the barrier.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Barriers {
+    state: Arc<Mutex<BarrierState>>,
+    notify: Arc<Notify>,
+}
+
+impl Barriers {
+    /// Register the tasks of one rule, and the barriers declared by the compiler of the rule
+    pub fn register(&self, names: &[String], tasks: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.running += tasks;
+        for name in names {
+            state.barriers.entry(name.clone()).or_default().expected += tasks;
+        }
+    }
+
+    /// Wait until all tasks which take part in the barrier arrive
+    pub async fn arrive(&self, name: &str) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(entry) = state.barriers.get_mut(name) else {
+                return Err(Error::Deadlock {
+                    trace: SpanTrace::capture(),
+                    message: format!("barrier `{}` is not declared by the compiler", name),
+                });
+            };
+            if entry.released {
+                return Ok(());
+            }
+            entry.arrived += 1;
+            state.running -= 1;
+            if state.try_release(name) {
+                self.notify.notify_waiters();
+                return Ok(());
+            }
+            if state.detect_deadlock() {
+                self.notify.notify_waiters();
+                return Err(state.deadlock_error());
+            }
+        }
+        loop {
+            let notified = self.notify.notified();
+            {
+                let state = self.state.lock().unwrap();
+                if state.barriers[name].released {
+                    return Ok(());
+                }
+                if state.deadlock.is_some() {
+                    return Err(state.deadlock_error());
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Mark the task blocked at a [`WaitStage`][crate::compiler::utils::WaitStage], which is
+    /// released only by other tasks of the rule
+    pub fn block_stage(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stage_waiting += 1;
+        if state.detect_deadlock() {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Mark the tasks released from [`WaitStage`][crate::compiler::utils::WaitStage]s
+    pub fn unblock_stages(&self, tasks: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.stage_waiting = state.stage_waiting.saturating_sub(tasks);
+    }
+
+    /// Finish the task, and leave the barriers which the task did not arrive at.
+    /// `waiting` is the barrier which the task arrived at but was not released, such as on
+    /// deadlock.
+    pub fn leave<'a>(&self, names: impl IntoIterator<Item = &'a String>, waiting: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        match waiting.and_then(|name| state.barriers.get_mut(name)) {
+            Some(entry) if !entry.released => entry.arrived -= 1,
+            _ => state.running -= 1,
+        }
+        for name in names {
+            if let Some(entry) = state.barriers.get_mut(name) {
+                entry.expected -= 1;
+                state.try_release(name);
+            }
+        }
+        state.detect_deadlock();
+        self.notify.notify_waiters();
+    }
+}
+
+/// Barriers of one compilation task, which leaves the barriers when dropped, even if the task
+/// panicked or was aborted
+pub(crate) struct TaskBarriers {
+    barriers: Barriers,
+    declared: Vec<String>,
+    arrived: HashSet<String>,
+    waiting: Option<String>,
+}
+
+impl TaskBarriers {
+    pub fn new(barriers: Barriers, declared: Vec<String>) -> Self {
+        Self {
+            barriers,
+            declared,
+            arrived: HashSet::new(),
+            waiting: None,
+        }
+    }
+    pub async fn arrive(&mut self, name: &str) -> Result<(), Error> {
+        if !self.arrived.insert(name.to_owned()) {
+            return Ok(());
+        }
+        self.waiting = Some(name.to_owned());
+        self.barriers.arrive(name).await?;
+        self.waiting = None;
+        Ok(())
+    }
+}
+
+impl Drop for TaskBarriers {
+    fn drop(&mut self) {
+        let mut declared: Vec<_> = self.declared.iter().collect();
+        declared.sort();
+        declared.dedup();
+        let left = declared.into_iter().filter(|n| !self.arrived.contains(*n));
+        self.barriers.leave(left, self.waiting.as_deref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        compiler::{metadata::SetMetadata, utils::*},
+        fs::MemoryFileSystem,
+        *,
+    };
+    use std::time::Duration;
+
+    fn set(key: &str) -> SetMetadata {
+        SetMetadata::new().local(key, true).unwrap()
+    }
+
+    fn site() -> Config {
+        let fs = MemoryFileSystem::new()
+            .with_file("site/posts/a", "")
+            .with_file("site/posts/b", "")
+            .with_file("site/posts/c", "")
+            .with_file("site/index", "");
+        Config::default().set_file_system(fs)
+    }
+
+    async fn build(builder: Builder) -> Result<BuildReport, Error> {
+        tokio::time::timeout(Duration::from_secs(10), builder.build())
+            .await
+            .expect("build hangs")
+    }
+
+    #[tokio::test]
+    async fn named_barrier() {
+        let is_c = |ctx: &Context| ctx.metadata().source().unwrap().ends_with("c");
+        // c leaves the barrier without arriving
+        let posts = If::new(is_c, set("parsed")).otherwise(pipe!(
+            set("parsed"),
+            set("slow"),
+            Barrier::new("parsed"),
+            set("done"),
+        ));
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    let posts = global["posts"].as_array().unwrap();
+                    assert_eq!(posts.len(), 3);
+                    assert!(posts.iter().all(|p| p["parsed"] == true));
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let index = pipe!(Barrier::new("parsed"), check);
+        let report = build(Builder::new(site()).add_step([
+            Rule::new("index", index).set_globs(["index"]),
+            Rule::new("posts", posts).set_globs(["posts/*"]),
+        ]))
+        .await
+        .unwrap();
+        assert_eq!(report.compiled.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn barrier_deadlock() {
+        let builder = Builder::new(site()).add_step([
+            Rule::new("posts", pipe!(Barrier::new("x"), Barrier::new("y"))).set_globs(["posts/*"]),
+            Rule::new("index", pipe!(Barrier::new("y"), Barrier::new("x"))).set_globs(["index"]),
+        ]);
+        let error = build(builder).await.unwrap_err();
+        assert!(matches!(error.cause(), Error::Deadlock { .. }));
+        assert!(error.to_string().contains("barrier deadlock"));
+    }
+
+    #[tokio::test]
+    async fn wait_stage_barrier_deadlock() {
+        let is_a = |ctx: &Context| ctx.metadata().source().unwrap().ends_with("a");
+        // a waits for the others at the WaitStage, and the others wait for a at the barrier
+        let compiler = If::new(is_a, pipe!(WaitStage::new(), Barrier::new("x")))
+            .otherwise(pipe!(Barrier::new("x"), WaitStage::new()));
+        let builder =
+            Builder::new(site()).add_step([Rule::new("posts", compiler).set_globs(["posts/*"])]);
+        let error = build(builder).await.unwrap_err();
+        assert!(matches!(error.cause(), Error::Deadlock { .. }));
+        assert!(error.to_string().contains("`WaitStage`"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn wait_stage_counts_waits() {
+        let is_a = |ctx: &Context| ctx.metadata().source().unwrap().ends_with("a");
+        let is_b = |ctx: &Context| ctx.metadata().source().unwrap().ends_with("b");
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    let versions = &global["_versions"]["default"];
+                    assert_eq!(versions["site/posts/c"]["second"], true);
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        // a waits before c reaches its WaitStage by more steps, and b never waits
+        let compiler = If::new(is_a, pipe!(WaitStage::new(), check)).otherwise(
+            If::new(is_b, set("first")).otherwise(pipe!(
+                set("first"),
+                set("second"),
+                WaitStage::new()
+            )),
+        );
+        let report = build(
+            Builder::new(site()).add_step([Rule::new("posts", compiler).set_globs(["posts/*"])]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.compiled.len(), 3);
+    }
+}
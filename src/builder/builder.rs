@@ -1,7 +1,10 @@
+use super::{barrier::Barriers, compile::panic_error, plan::*, report::*};
 use crate::*;
 use log::info;
-use std::fs::remove_dir_all;
+use std::collections::HashSet;
+use std::path::PathBuf;
 use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 use tracing_error::SpanTrace;
 
 /// A site builder to use build one site
@@ -19,35 +22,176 @@ impl Builder {
         }
     }
 
+    /// Set the token to cancel the build, such as when sources are changed during the build
+    /// in watch mode. Running compilation tasks are aborted, and [`Error::Cancelled`] is
+    /// returned.
+    pub fn set_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.ctx.set_cancellation_token(token);
+        self
+    }
+
     /// Add a new build step with multiple rules that are built concurrently.
+    /// Source files are matched by the rules in order, so the first rule matching a source wins
+    /// for the [`Version`].
+    /// Tasks of the rules in the same step may wait for each other at named
+    /// [`Barrier`][crate::compiler::utils::Barrier]s.
     pub fn add_step(mut self, step: impl IntoIterator<Item = Rule>) -> Self {
         self.steps.push(step.into_iter().collect());
         self
     }
 
-    /// Run all registered build steps
+    /// Match source files to rules, without running compilers or touching the target directory.
+    ///
+    /// In each step, rules claim the matched sources in order, and the first rule to claim a
+    /// source wins for the [`Version`]. The same matching is used by [`Builder::build`].
     #[tracing::instrument(skip(self))]
-    pub async fn build(self) -> Result<(), Error> {
-        let conf = self.ctx.config();
-        let target_dir = conf.target_dir();
-        if conf.target_clean() && target_dir.is_dir() {
-            remove_dir_all(&target_dir).map_err(|io_error| Error::FileIo {
+    pub fn plan(&self) -> Result<BuildPlan, Error> {
+        let config = self.ctx.config();
+        let mut claims = Claims::default();
+        let mut plan = BuildPlan::default();
+        let mut matched = HashSet::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            for rule in step {
+                let rule_plan = rule.plan(&config, i, &mut claims)?;
+                let rule_plan = rule.check_publication_blocking(&config, rule_plan)?;
+                if rule_plan.tasks.is_empty() {
+                    plan.unused_rules.push(UnusedRule {
+                        step: i,
+                        rule: rule.get_name().to_owned(),
+                        matched: rule_plan.matched.len(),
+                    });
+                }
+                matched.extend(rule_plan.matched);
+                plan.tasks.extend(rule_plan.tasks);
+                plan.skipped.extend(rule_plan.skipped);
+            }
+        }
+        let all = config.source_dir().join("**/*");
+        plan.unmatched = config
+            .file_system()
+            .glob(&all.to_string_lossy())
+            .map_err(|io_error| Error::FileIo {
                 trace: SpanTrace::capture(),
                 io_error,
-            })?;
+            })?
+            .into_iter()
+            .filter(|p| !matched.contains(p))
+            .collect();
+        Ok(plan)
+    }
+
+    /// Run all registered build steps, and returns [`BuildReport`].
+    ///
+    /// By default, the build is aborted on the first error.
+    /// If [`Config::keep_going`] is set, the remaining files are compiled and the failures,
+    /// including panics of compilers, are collected into the report. In that case, if
+    /// [`Config::strict`] is set, [`Error::Build`] is returned when some tasks failed.
+    #[tracing::instrument(skip(self))]
+    pub async fn build(mut self) -> Result<BuildReport, Error> {
+        self.ctx.start_clock();
+        let conf = self.ctx.config();
+        let target_dir = conf.target_dir();
+        if conf.target_clean() && conf.file_system().is_dir(&target_dir) {
+            let dir = target_dir.clone();
+            self.ctx
+                .with_file_system(move |fs| fs.remove_dir_all(&dir))
+                .await?;
             info!("Target directory ({}) cleaned", target_dir.display());
         }
-        for step in self.steps.into_iter() {
-            let mut set = JoinSet::new();
+        let token = self.ctx.cancellation_token();
+        let mut report = BuildReport::new();
+        let mut claims = Claims::default();
+        for (i, step) in self.steps.into_iter().enumerate() {
+            if token.is_cancelled() {
+                return Err(Error::Cancelled);
+            }
+            // sources may be added to versions by compilers
+            {
+                let locked = self.ctx.metadata().read_lock().await;
+                for rule in &step {
+                    let version = rule.get_version();
+                    for source in locked
+                        .get_version(version)
+                        .into_iter()
+                        .flat_map(|v| v.into_keys())
+                    {
+                        claims.claim(version, PathBuf::from(source));
+                    }
+                }
+            }
+            // all tasks of the step take part in the barriers before any task starts
+            let mut plans = Vec::new();
             for rule in step.into_iter() {
+                let plan = match rule.plan(&conf, i, &mut claims) {
+                    Ok(plan) => rule.check_publication(&self.ctx, plan).await,
+                    Err(error) => Err(error),
+                };
+                match plan {
+                    Ok(plan) => plans.push((rule, plan)),
+                    Err(error) if conf.keep_going() => {
+                        log::error!("{}", error.report());
+                        report.failed.push(FailedTask {
+                            rule: rule.get_name().to_owned(),
+                            source: None,
+                            error,
+                        });
+                    }
+                    Err(error) => return Err(error),
+                }
+            }
+            let barriers = Barriers::default();
+            for (rule, plan) in &plans {
+                barriers.register(&rule.barriers(), plan.tasks.len());
+            }
+            self.ctx.set_barriers(barriers);
+            let mut set = JoinSet::new();
+            for (rule, plan) in plans {
                 let ctx = self.ctx.clone();
-                set.spawn(rule.compile(ctx));
+                let name = rule.get_name().to_owned();
+                set.spawn(async move { (name, rule.compile(ctx, plan).await) });
             }
-            while let Some(res) = set.join_next().await {
-                let _ctx = res.unwrap()?;
-                //self.ctx.metadata_mut().merge(ctx.metadata().clone());
+            loop {
+                let res = tokio::select! {
+                    res = set.join_next() => match res {
+                        Some(res) => res,
+                        None => break,
+                    },
+                    _ = token.cancelled() => {
+                        set.shutdown().await;
+                        info!("Build cancelled");
+                        return Err(Error::Cancelled);
+                    }
+                };
+                let (rule, res) = res.map_err(panic_error)?;
+                match res {
+                    Ok(r) => report.merge(r),
+                    Err(error) if conf.keep_going() => {
+                        log::error!("{}", error.report());
+                        report.failed.push(FailedTask {
+                            rule,
+                            source: None,
+                            error,
+                        });
+                    }
+                    Err(error) => {
+                        set.shutdown().await;
+                        return Err(error);
+                    }
+                }
             }
         }
-        Ok(())
+        report.duration = self.ctx.elapsed();
+        info!(
+            "Built {} files ({} bytes) in {:.2?}",
+            report.compiled.len(),
+            report.bytes_written(),
+            report.duration
+        );
+        if conf.strict() && !report.is_success() {
+            return Err(Error::Build {
+                report: Box::new(report),
+            });
+        }
+        Ok(report)
     }
 }
@@ -0,0 +1,194 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+
+/// [`Collection`] is a list of rule results, which is used to sort, filter, group and limit
+/// pages, such as posts in an archive page. Use [`Context::collection`][crate::Context::collection]
+/// to get the results of the rule.
+///
+/// Keys may be dotted paths, such as `author.name`.
+/// Numbers are compared numerically, strings, including ISO 8601 dates, lexicographically, and
+/// dates normalized by [`ParseDate`][crate::compiler::date::ParseDate] by the timestamp.
+/// Pages without the key are placed last.
+///
+/// # Example
+/// ```
+/// use polysite::{builder::collection::Collection, *};
+/// use serde_json::json;
+/// let posts = Collection::new(vec![
+///     json!({"title": "a", "date": "2024-01-02", "draft": false}),
+///     json!({"title": "b", "date": "2023-05-01", "draft": false}),
+///     json!({"title": "c", "date": "2024-03-01", "draft": true}),
+/// ]);
+/// let recent = posts
+///     .sort_by_desc("date")
+///     .filter_by("draft", false)
+///     .take(10);
+/// assert_eq!(recent.items()[0]["title"], "a");
+/// let years = recent.group_by_year("date");
+/// assert_eq!(years[0].key, "2024");
+/// ```
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Collection {
+    items: Vec<Value>,
+}
+
+/// Group of pages created by [`Collection::group_by`]
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Group {
+    pub key: Value,
+    pub items: Collection,
+}
+
+impl Collection {
+    pub fn new(items: Vec<Value>) -> Self {
+        Self { items }
+    }
+
+    /// Sort by the key in ascending order
+    pub fn sort_by(mut self, key: impl AsRef<str>) -> Self {
+        let key = key.as_ref();
+        self.items
+            .sort_by(|a, b| compare_values(lookup(a, key), lookup(b, key)));
+        self
+    }
+    /// Sort by the key in descending order. Pages without the key are still placed last.
+    pub fn sort_by_desc(mut self, key: impl AsRef<str>) -> Self {
+        let key = key.as_ref();
+        self.items
+            .sort_by(|a, b| match (lookup(a, key), lookup(b, key)) {
+                (Some(a), Some(b)) => compare_values(Some(b), Some(a)),
+                (a, b) => compare_values(a, b),
+            });
+        self
+    }
+    /// Reverse the order
+    pub fn rev(mut self) -> Self {
+        self.items.reverse();
+        self
+    }
+    /// Keep pages matching the predicate
+    pub fn filter(mut self, f: impl Fn(&Value) -> bool) -> Self {
+        self.items.retain(|v| f(v));
+        self
+    }
+    /// Keep pages whose value of the key equals to the value
+    pub fn filter_by(self, key: impl AsRef<str>, value: impl Into<Value>) -> Self {
+        let key = key.as_ref();
+        let value = value.into();
+        self.filter(|v| lookup(v, key) == Some(&value))
+    }
+    /// Keep the first `n` pages
+    pub fn take(mut self, n: usize) -> Self {
+        self.items.truncate(n);
+        self
+    }
+    /// Skip the first `n` pages
+    pub fn skip(mut self, n: usize) -> Self {
+        self.items.drain(..n.min(self.items.len()));
+        self
+    }
+    /// Group pages by the value of the key, in order of first appearance.
+    /// Pages without the key are grouped with `null` key.
+    pub fn group_by(self, key: impl AsRef<str>) -> Vec<Group> {
+        let key = key.as_ref();
+        self.group_with(|v| lookup(v, key).cloned().unwrap_or(Value::Null))
+    }
+    /// Group pages by the year of the date of the key, such as `2024`
+    pub fn group_by_year(self, key: impl AsRef<str>) -> Vec<Group> {
+        let key = key.as_ref();
+        self.group_with(|v| date_prefix(lookup(v, key), 4))
+    }
+    /// Group pages by the month of the date of the key, such as `2024-01`
+    pub fn group_by_month(self, key: impl AsRef<str>) -> Vec<Group> {
+        let key = key.as_ref();
+        self.group_with(|v| date_prefix(lookup(v, key), 7))
+    }
+    fn group_with(self, f: impl Fn(&Value) -> Value) -> Vec<Group> {
+        let mut groups: Vec<Group> = Vec::new();
+        for item in self.items {
+            let key = f(&item);
+            match groups.iter_mut().find(|g| g.key == key) {
+                Some(group) => group.items.items.push(item),
+                None => groups.push(Group {
+                    key,
+                    items: Collection::new(vec![item]),
+                }),
+            }
+        }
+        groups
+    }
+
+    pub fn items(&self) -> &[Value] {
+        &self.items
+    }
+    pub fn into_vec(self) -> Vec<Value> {
+        self.items
+    }
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+impl IntoIterator for Collection {
+    type Item = Value;
+    type IntoIter = std::vec::IntoIter<Value>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl From<Collection> for Value {
+    fn from(collection: Collection) -> Self {
+        Value::Array(collection.items)
+    }
+}
+
+impl From<Group> for Value {
+    fn from(group: Group) -> Self {
+        json!({ "key": group.key, "items": Value::from(group.items) })
+    }
+}
+
+/// Get the value of the dotted key
+pub(crate) fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.')
+        .try_fold(value, |v, k| match v {
+            Value::Array(array) => k.parse::<usize>().ok().and_then(|i| array.get(i)),
+            v => v.get(k),
+        })
+        .filter(|v| !v.is_null())
+}
+
+/// Compare values. [`None`] is greater than any value.
+pub(crate) fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (None, None) => Ordering::Equal,
+        (None, _) => Ordering::Greater,
+        (_, None) => Ordering::Less,
+        (Some(Value::Number(a)), Some(Value::Number(b))) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(a @ Value::Object(_)), Some(b @ Value::Object(_)))
+            if a.get("timestamp").is_some() && b.get("timestamp").is_some() =>
+        {
+            compare_values(a.get("timestamp"), b.get("timestamp"))
+        }
+        (Some(a), Some(b)) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+fn date_prefix(value: Option<&Value>, len: usize) -> Value {
+    value
+        .and_then(|v| v.get("iso").unwrap_or(v).as_str())
+        .and_then(|s| s.get(..len))
+        .map(Value::from)
+        .unwrap_or(Value::Null)
+}
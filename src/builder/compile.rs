@@ -1,43 +1,41 @@
-use super::metadata::*;
+use super::{barrier::TaskBarriers, metadata::*, report::*, rule::Publish};
 use crate::*;
-use serde_json::{json, Map, Value};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
-    sync::{Notify, RwLock},
-    task::JoinSet,
+    sync::{Notify, OwnedSemaphorePermit, RwLock, Semaphore},
+    task::{Id, JoinError, JoinSet},
 };
+use tokio_util::task::AbortOnDropHandle;
 
+/// State shared by the compilation tasks of one rule
 #[derive(Clone)]
-pub(crate) struct CompileRunner {
+struct RuleState {
     rule: String,
     version: Version,
     context: Context,
-    compiler: Box<dyn Compiler>,
     results: Arc<RwLock<Vec<(usize, Metadata)>>>,
-    tasks: Arc<RwLock<JoinSet<Result<Context, Error>>>>,
+    /// Tasks blocked at a [`WaitStage`][crate::compiler::utils::WaitStage], which is updated while
+    /// `results` is locked
+    blocked: Arc<std::sync::Mutex<HashSet<usize>>>,
     notify: Arc<Notify>,
+    limit: Arc<Semaphore>,
+    publish: Publish,
+    timeout: Option<Duration>,
 }
 
-impl CompileRunner {
-    pub fn new(
-        rule: String,
-        version: Version,
-        context: Context,
-        compiler: Box<dyn Compiler>,
-    ) -> Self {
-        Self {
-            rule,
-            version,
-            context,
-            compiler,
-            results: Arc::new(RwLock::new(Vec::new())),
-            tasks: Arc::new(RwLock::new(JoinSet::new())),
-            notify: Arc::new(Notify::new()),
-        }
+impl RuleState {
+    /// Acquire the permits of the rule and the whole build to run one stage
+    async fn acquire(&self) -> (OwnedSemaphorePermit, OwnedSemaphorePermit) {
+        let rule = self.limit.clone().acquire_owned().await.unwrap();
+        let global = self.context.task_limit().acquire_owned().await.unwrap();
+        (rule, global)
     }
 
-    pub async fn update_context(&self) {
+    async fn update_context(&self) {
         let res: Vec<_> = self
             .results
             .read()
@@ -47,13 +45,12 @@ impl CompileRunner {
                 meta.source().map(|s| {
                     (
                         s.to_string_lossy().to_string(),
-                        Value::Object(meta.local().clone()),
+                        Value::Object(self.publish.filter(meta.published_local())),
                     )
                 })
             })
             .collect();
         {
-            let map = Map::from_iter(res.clone().into_iter());
             let mut global = self.context.metadata().global_mut().await;
             let versions = global
                 .get_mut(VERSIONS_META)
@@ -67,7 +64,11 @@ impl CompileRunner {
                     versions.get_mut(self.version.get()).unwrap()
                 }
             };
-            merge_values(version, Value::Object(map));
+            // replace each result, since merging would duplicate arrays of earlier stages
+            let version = version.as_object_mut().unwrap();
+            for (source, meta) in res.iter().cloned() {
+                version.insert(source, meta);
+            }
         }
         let res = res.into_iter().map(|(_, v)| v).collect();
         self.context
@@ -76,98 +77,315 @@ impl CompileRunner {
             .await;
     }
 
+    /// Save the result of one stage, and returns the number of [`WaitStage`]s the task reached
+    ///
+    /// [`WaitStage`]: crate::compiler::utils::WaitStage
+    async fn finish_stage(&self, task_id: usize, ctx: &Context, wait: bool) -> usize {
+        let waits = {
+            let mut results = self.results.write().await;
+            let (waits, meta) = &mut results[task_id];
+            if wait {
+                *waits += 1;
+            }
+            *meta = ctx.metadata().clone();
+            let waits = *waits;
+            self.unblock(&results);
+            waits
+        };
+        self.update_context().await;
+        self.notify.notify_waiters();
+        waits
+    }
+
+    /// Mark the task as finished, so that other tasks do not wait for it
+    async fn finish(&self, task_id: usize) {
+        let mut results = self.results.write().await;
+        results[task_id].0 = usize::MAX;
+        self.unblock(&results);
+        self.notify.notify_waiters();
+    }
+
+    /// Mark the blocked tasks which can pass their [`WaitStage`]s as running for the deadlock
+    /// detection of the barriers, before they are woken up
+    ///
+    /// [`WaitStage`]: crate::compiler::utils::WaitStage
+    fn unblock(&self, results: &[(usize, Metadata)]) {
+        let min = results.iter().map(|(w, _)| *w).min().unwrap_or(usize::MAX);
+        let mut blocked = self.blocked.lock().unwrap();
+        let before = blocked.len();
+        blocked.retain(|task_id| min < results[*task_id].0);
+        self.context
+            .barriers()
+            .unblock_stages(before - blocked.len());
+    }
+
+    /// Wait until all other tasks reach the number of [`WaitStage`]s, or complete
+    ///
+    /// [`WaitStage`]: crate::compiler::utils::WaitStage
+    async fn wait_stage(&self, task_id: usize, waits: usize) {
+        loop {
+            let notified = self.notify.notified();
+            {
+                let results = self.results.read().await;
+                let min = results.iter().map(|(w, _)| *w).min();
+                if min.is_none_or(|min| waits <= min) {
+                    break;
+                }
+                // tasks blocked here can only be released by other tasks of the rule
+                if self.blocked.lock().unwrap().insert(task_id) {
+                    self.context.barriers().block_stage();
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Run one step of the compiler within the timeout
+    async fn next_step(
+        &self,
+        compiler: &mut Box<dyn Compiler>,
+        ctx: Context,
+        index: usize,
+    ) -> Result<CompileStep, Error> {
+        let Some(timeout) = self.timeout else {
+            return compiler.next_step(ctx).await;
+        };
+        // the step runs in another task, which is aborted on expiry or when this task is aborted
+        let handle = AbortOnDropHandle::new(tokio::spawn(compiler.next_step(ctx)));
+        match tokio::time::timeout(timeout, handle).await {
+            Ok(res) => res.map_err(panic_error)?,
+            Err(_) => {
+                let error = Error::Timeout {
+                    timeout,
+                    step: index,
+                };
+                // the stage of the running compiler, as errors returned by the compiler have
+                Err(compiler
+                    .stage()
+                    .into_iter()
+                    .rev()
+                    .fold(error, |error, i| error.with_stage(i)))
+            }
+        }
+    }
+
+    async fn run(
+        &self,
+        task_id: usize,
+        mut compiler: Box<dyn Compiler>,
+        mut ctx: Context,
+        source: PathBuf,
+        barriers: &mut TaskBarriers,
+    ) -> Result<CompiledTask, Error> {
+        let task_start = ctx.elapsed();
+        let mut stages = Vec::new();
+        loop {
+            let queued = ctx.elapsed();
+            let permits = self.acquire().await;
+            let start = ctx.elapsed();
+            let mut step = self.next_step(&mut compiler, ctx, stages.len()).await?;
+            step.context_mut().write_emitted().await?;
+            let mut timing = StageTiming {
+                index: stages.len(),
+                start,
+                queue: start - queued,
+                duration: Duration::ZERO,
+                wait: Duration::ZERO,
+            };
+            match step {
+                CompileStep::Completed(v) => {
+                    timing.duration = v.elapsed() - start;
+                    stages.push(timing);
+                    self.finish_stage(task_id, &v, false).await;
+                    self.finish(task_id).await;
+                    return Ok(CompiledTask {
+                        rule: self.rule.clone(),
+                        version: self.version.get().to_owned(),
+                        source,
+                        target: v.target().await,
+                        written: v.written(),
+                        start: task_start,
+                        duration: v.elapsed() - task_start,
+                        stages,
+                    });
+                }
+                CompileStep::InProgress(v) => {
+                    timing.duration = v.elapsed() - start;
+                    stages.push(timing);
+                    self.finish_stage(task_id, &v, false).await;
+                    ctx = v;
+                }
+                CompileStep::WaitStage(v) => {
+                    timing.duration = v.elapsed() - start;
+                    // other tasks may need the permits to reach this stage
+                    drop(permits);
+                    let waits = self.finish_stage(task_id, &v, true).await;
+                    ctx = v;
+                    let wait_start = ctx.elapsed();
+                    self.wait_stage(task_id, waits).await;
+                    timing.wait = ctx.elapsed() - wait_start;
+                    stages.push(timing);
+                }
+                CompileStep::WaitBarrier(name, v) => {
+                    timing.duration = v.elapsed() - start;
+                    drop(permits);
+                    self.finish_stage(task_id, &v, false).await;
+                    ctx = v;
+                    let wait_start = ctx.elapsed();
+                    barriers.arrive(&name).await?;
+                    timing.wait = ctx.elapsed() - wait_start;
+                    stages.push(timing);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct CompileRunner {
+    state: RuleState,
+    compiler: Box<dyn Compiler>,
+    keep_going: bool,
+    tasks: JoinSet<Result<CompiledTask, Error>>,
+    sources: HashMap<Id, (usize, PathBuf)>,
+    spawned: usize,
+}
+
+impl CompileRunner {
+    /// Create the runner of the rule, which runs the specified number of tasks.
+    /// The tasks are registered beforehand, so that tasks spawned earlier do not pass
+    /// [`WaitStage`][crate::compiler::utils::WaitStage] before others are spawned.
+    pub fn new(
+        tasks: usize,
+        rule: String,
+        version: Version,
+        context: Context,
+        compiler: Box<dyn Compiler>,
+        max_tasks: Option<usize>,
+        publish: Publish,
+    ) -> Self {
+        let keep_going = context.config().keep_going();
+        let limit = max_tasks.unwrap_or(Semaphore::MAX_PERMITS);
+        Self {
+            state: RuleState {
+                rule,
+                version,
+                context,
+                results: Arc::new(RwLock::new(vec![(0, Metadata::new()); tasks])),
+                blocked: Arc::new(std::sync::Mutex::new(HashSet::new())),
+                notify: Arc::new(Notify::new()),
+                limit: Arc::new(Semaphore::new(limit)),
+                publish,
+                timeout: None,
+            },
+            compiler,
+            keep_going,
+            tasks: JoinSet::new(),
+            sources: HashMap::new(),
+            spawned: 0,
+        }
+    }
+
+    /// Set the timeout of each step of the tasks
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.state.timeout = timeout;
+        self
+    }
+
     #[tracing::instrument(skip(self))]
-    pub async fn spawn_compile(&self, source: PathBuf, target: PathBuf, path: PathBuf) {
-        let mut s = self.clone();
-        s.context
-            .metadata_mut()
-            .insert_local(RULE_META.to_owned(), Value::from(self.rule.clone()));
-        s.context.metadata_mut().insert_local(
+    pub async fn spawn_compile(&mut self, source: PathBuf, target: PathBuf, path: PathBuf) {
+        let state = self.state.clone();
+        let mut ctx = state.context.clone();
+        ctx.start_task();
+        let meta = ctx.metadata_mut();
+        meta.insert_local(RULE_META.to_owned(), Value::from(state.rule.clone()));
+        meta.insert_local(
             VERSION_META.to_owned(),
-            Value::from(self.version.get().to_owned()),
+            Value::from(state.version.get().to_owned()),
         );
-        s.context.metadata_mut().insert_local(
+        meta.insert_local(
             SOURCE_FILE_META.to_owned(),
             Value::from(source.to_string_lossy()),
         );
-        s.context.metadata_mut().insert_local(
+        meta.insert_local(
             TARGET_FILE_META.to_owned(),
             Value::from(target.to_string_lossy()),
         );
-        s.context
-            .metadata_mut()
-            .insert_local(PATH_META.to_owned(), Value::from(path.to_string_lossy()));
-
-        let task_id = {
-            let mut write = s.results.write().await;
-            write.push((0, Metadata::new()));
-            write.len() - 1
-        };
+        meta.insert_local(PATH_META.to_owned(), Value::from(path.to_string_lossy()));
 
-        self.tasks.write().await.spawn(async move {
-            let mut ctx = s.context.clone();
-            loop {
-                match s.compiler.next_step(ctx).await? {
-                    CompileStep::Completed(v) => {
-                        ctx = v;
-                        {
-                            let (stage, meta) = &mut s.results.write().await[task_id];
-                            *stage += 1;
-                            *meta = ctx.metadata().clone();
-                        }
-                        s.update_context().await;
-                        s.notify.notify_waiters();
-                        return Ok(ctx);
-                    }
-                    CompileStep::InProgress(v) => {
-                        ctx = v;
-                        {
-                            let (stage, meta) = &mut s.results.write().await[task_id];
-                            *stage += 1;
-                            *meta = ctx.metadata().clone();
-                        }
-                        s.update_context().await;
-                        s.notify.notify_waiters();
-                    }
-                    CompileStep::WaitStage(v) => {
-                        ctx = v;
-                        let stage;
-                        {
-                            let (s, meta) = &mut s.results.write().await[task_id];
-                            *s += 1;
-                            *meta = ctx.metadata().clone();
-                            stage = *s;
-                        }
-                        s.update_context().await;
-                        s.notify.notify_waiters();
-                        loop {
-                            if let Some(min) =
-                                s.results.read().await.iter().map(|(stage, _)| *stage).min()
-                            {
-                                if stage <= min {
-                                    break;
-                                }
-                            }
-                            s.notify.notified().await;
-                        }
-                    }
-                }
+        let task_id = self.spawned;
+        self.spawned += 1;
+
+        let compiler = self.compiler.fresh();
+        let mut barriers = TaskBarriers::new(ctx.barriers(), compiler.barriers());
+        let task_source = source.clone();
+        let handle = self.tasks.spawn(async move {
+            let res = state
+                .run(task_id, compiler, ctx, task_source.clone(), &mut barriers)
+                .await;
+            if res.is_err() {
+                state.finish(task_id).await;
             }
+            res.map_err(|e| e.with_task(&state.rule, task_source))
         });
+        self.sources.insert(handle.id(), (task_id, source));
     }
 
+    /// Wait for all tasks. If the runner does not keep going, returns the first error and aborts
+    /// the remaining tasks.
     #[tracing::instrument(skip(self))]
-    pub async fn join(self) -> Result<Context, Error> {
-        let mut ctx = self.context;
-        let mut tasks = self.tasks.write().await;
-        while let Some(res) = tasks.join_next().await {
-            ctx = res.unwrap()?;
-            log::info!(
-                "Compiled: {} -> {}",
-                ctx.source().await.unwrap().display(),
-                ctx.target().await.unwrap().display(),
-            );
+    pub async fn join(mut self) -> Result<BuildReport, Error> {
+        let mut report = BuildReport::new();
+        while let Some(res) = self.tasks.join_next_with_id().await {
+            let (id, res) = match res {
+                Ok((id, res)) => (id, res),
+                Err(join_error) => (join_error.id(), Err(panic_error(join_error))),
+            };
+            let (task_id, source) = self.sources.remove(&id).unwrap();
+            match res {
+                Ok(task) => {
+                    log::info!(
+                        "Compiled: {} -> {}",
+                        source.display(),
+                        task.target
+                            .as_ref()
+                            .map(|t| t.display().to_string())
+                            .unwrap_or_default(),
+                    );
+                    report.compiled.push(task);
+                }
+                Err(error) => {
+                    // panicked task could not mark itself
+                    self.state.finish(task_id).await;
+                    let error = match error {
+                        error @ Error::Panic { .. } => error.with_task(&self.state.rule, &source),
+                        error => error,
+                    };
+                    if !self.keep_going {
+                        self.tasks.shutdown().await;
+                        return Err(error);
+                    }
+                    log::error!("{}", error.report());
+                    report.failed.push(FailedTask {
+                        rule: self.state.rule.clone(),
+                        source: Some(source),
+                        error,
+                    });
+                }
+            }
         }
-        Ok(ctx)
+        Ok(report)
     }
 }
+
+/// Convert the panic of the task into [`Error::Panic`]
+pub(crate) fn panic_error(join_error: JoinError) -> Error {
+    let message = match join_error.try_into_panic() {
+        Ok(panic) => panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic".to_owned()),
+        Err(join_error) => join_error.to_string(),
+    };
+    Error::Panic { message }
+}
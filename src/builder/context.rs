@@ -1,7 +1,16 @@
-use super::metadata::*;
-use crate::*;
-use std::fs;
-use std::path::PathBuf;
+use super::{
+    barrier::Barriers, collection::Collection, compile::panic_error, metadata::*,
+    report::WrittenFile,
+};
+use crate::{error::CodeFrame, fs::FileSystem, *};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 use tracing_error::SpanTrace;
 
 /// [`Version`] represents the compilation file version. Once a source file has been built, any
@@ -24,22 +33,100 @@ impl<S: AsRef<str>> From<S> for Version {
     }
 }
 
+/// Additional output file queued by [`Context::emit`]
+#[derive(Clone, Debug)]
+struct Emitted {
+    target: PathBuf,
+    path: PathBuf,
+    body: Bytes,
+}
+
 #[derive(Clone)]
 /// [`Context`] holds [`Metadata`] and [`Config`].
 /// The context is passed to [`Compiler::next_step`], and the modified context is returned.
 pub struct Context {
     meta: Metadata,
     config: Config,
+    epoch: Instant,
+    written: Arc<Mutex<Vec<WrittenFile>>>,
+    emitted: Arc<Mutex<Vec<Emitted>>>,
+    io: Arc<Semaphore>,
+    exec: Arc<Semaphore>,
+    tasks: Arc<Semaphore>,
+    barriers: Barriers,
+    cancel: CancellationToken,
 }
 
 impl Context {
     pub fn new(config: Config) -> Self {
         Self {
-            meta: Metadata::new(),
+            meta: Metadata::with_global(config.global_metadata()),
+            io: Arc::new(Semaphore::new(config.io_concurrency())),
+            exec: Arc::new(Semaphore::new(config.exec_concurrency())),
+            tasks: Arc::new(Semaphore::new(
+                config.max_tasks().unwrap_or(Semaphore::MAX_PERMITS),
+            )),
             config,
+            epoch: Instant::now(),
+            written: Arc::new(Mutex::new(Vec::new())),
+            emitted: Arc::new(Mutex::new(Vec::new())),
+            barriers: Barriers::default(),
+            cancel: CancellationToken::new(),
         }
     }
 
+    /// Reset the build clock
+    pub(crate) fn start_clock(&mut self) {
+        self.epoch = Instant::now();
+    }
+    /// Get elapsed time since the build started
+    pub(crate) fn elapsed(&self) -> Duration {
+        self.epoch.elapsed()
+    }
+    /// Get the semaphore limiting running compilation tasks in the whole build
+    pub(crate) fn task_limit(&self) -> Arc<Semaphore> {
+        self.tasks.clone()
+    }
+    /// Get the semaphore limiting running external commands
+    pub(crate) fn exec_limit(&self) -> Arc<Semaphore> {
+        self.exec.clone()
+    }
+    /// Get the barriers of the current build step
+    pub(crate) fn barriers(&self) -> Barriers {
+        self.barriers.clone()
+    }
+    /// Set the barriers of new build step
+    pub(crate) fn set_barriers(&mut self, barriers: Barriers) {
+        self.barriers = barriers;
+    }
+    /// Get the token which cancels the build.
+    /// Long running compilers may stop early when the token is cancelled.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+    /// Set the token which cancels the build
+    pub(crate) fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+    /// Start recording written files of new compilation task
+    pub(crate) fn start_task(&mut self) {
+        self.written = Arc::new(Mutex::new(Vec::new()));
+        self.emitted = Arc::new(Mutex::new(Vec::new()));
+    }
+    /// Get files written in the compilation task
+    pub(crate) fn written(&self) -> Vec<WrittenFile> {
+        self.written.lock().unwrap().clone()
+    }
+    /// Record the file written in the compilation task, which is listed in
+    /// [`BuildReport`].
+    /// Compilers which write files by themselves should call this.
+    pub fn record_write(&self, path: impl AsRef<Path>, bytes: u64) {
+        self.written.lock().unwrap().push(WrittenFile {
+            path: path.as_ref().to_owned(),
+            bytes,
+        });
+    }
+
     pub fn metadata(&self) -> &Metadata {
         &self.meta
     }
@@ -52,8 +139,7 @@ impl Context {
         self.meta
             .get(VERSION_META)
             .await
-            .map(|v| v.as_str().map(|v| v.into()))
-            .flatten()
+            .and_then(|v| v.as_str().map(|v| v.into()))
     }
 
     /// Get currently compiling rule name
@@ -61,8 +147,7 @@ impl Context {
         self.meta
             .get(RULE_META)
             .await
-            .map(|v| v.as_str().map(|v| v.to_owned()))
-            .flatten()
+            .and_then(|v| v.as_str().map(|v| v.to_owned()))
     }
 
     /// Get currently compiling source file path
@@ -70,69 +155,128 @@ impl Context {
         self.meta
             .get(SOURCE_FILE_META)
             .await
-            .map(|v| v.as_str().map(|v| PathBuf::from(v)))
-            .flatten()
+            .and_then(|v| v.as_str().map(PathBuf::from))
     }
     /// Get currently compiling target file path
     pub async fn target(&self) -> Option<PathBuf> {
         self.meta
             .get(TARGET_FILE_META)
             .await
-            .map(|v| v.as_str().map(|v| PathBuf::from(v)))
-            .flatten()
+            .and_then(|v| v.as_str().map(PathBuf::from))
     }
     /// Get currently compiling URL path
     pub async fn path(&self) -> Option<PathBuf> {
         self.meta
             .get(PATH_META)
             .await
-            .map(|v| v.as_str().map(|v| PathBuf::from(v)))
-            .flatten()
+            .and_then(|v| v.as_str().map(PathBuf::from))
     }
-    /// Get currently compiling body [`Value`], which can be [`Vec<u8>`] or [`String`].
+    /// Get currently compiling body [`Value`].
+    /// If the body is binary, this returns the marker object. Use [`Context::body_bytes`] to get
+    /// binary body.
     pub async fn body(&self) -> Option<Value> {
         self.meta.get(BODY_META).await
     }
+    /// Get currently compiling body as [`Bytes`], which may be binary data or a string.
+    pub fn body_bytes(&self) -> Option<Bytes> {
+        self.meta.body_bytes()
+    }
+    /// Get the results of the rule as [`Collection`], which is in the order of source files.
+    pub async fn collection(&self, rule: impl AsRef<str>) -> Collection {
+        let items = self
+            .meta
+            .global()
+            .await
+            .get(rule.as_ref())
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+        Collection::new(items)
+    }
+    /// Deserialize all local metadata, such as front matter keys, into `T`.
+    ///
+    /// # Example
+    /// ```
+    /// use polysite::*;
+    /// #[derive(serde::Deserialize)]
+    /// struct Post {
+    ///     title: String,
+    ///     tags: Option<Vec<String>>,
+    /// }
+    /// Rule::new("posts", |ctx: Context| {
+    ///     compile!({
+    ///         let post: Post = ctx.front_matter()?;
+    ///         println!("{}", post.title);
+    ///         Ok(CompileStep::Completed(ctx))
+    ///     })
+    /// });
+    /// ```
+    pub fn front_matter<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        self.meta.local_as()
+    }
     /// Get [`Config`]
     pub fn config(&self) -> Config {
         self.config.clone()
     }
+    /// Get [`FileSystem`] set in [`Config`]
+    pub fn file_system(&self) -> Arc<dyn FileSystem> {
+        self.config.file_system()
+    }
+
+    /// Run the blocking operation of [`FileSystem`] on the blocking thread pool, so that it does
+    /// not block other compilation tasks.
+    /// The number of concurrent operations is limited by [`Config::io_concurrency`].
+    pub async fn with_file_system<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn FileSystem) -> io::Result<T> + Send + 'static,
+    {
+        let _permit = self.io.acquire().await.unwrap();
+        let fs = self.file_system();
+        tokio::task::spawn_blocking(move || f(fs.as_ref()))
+            .await
+            .map_err(panic_error)?
+            .map_err(|io_error| Error::FileIo {
+                trace: SpanTrace::capture(),
+                io_error,
+            })
+    }
 
     /// Get source file body as bytes
     #[tracing::instrument(skip(self))]
     pub async fn source_body(&self) -> Result<Vec<u8>, Error> {
-        let file = self.source().await.ok_or(Error::InvalidMetadata {
-            trace: SpanTrace::capture(),
-        })?;
-        fs::read(&file).map_err(|io_error| Error::FileIo {
-            trace: SpanTrace::capture(),
-            io_error,
-        })
+        let file = self
+            .source()
+            .await
+            .ok_or_else(|| Error::missing_metadata(SOURCE_FILE_META))?;
+        self.with_file_system(move |fs| fs.read(&file)).await
     }
     /// Get source file string
     #[tracing::instrument(skip(self))]
     pub async fn source_string(&self) -> Result<String, Error> {
-        String::from_utf8(self.source_body().await?).map_err(|_| Error::InvalidMetadata {
-            trace: SpanTrace::capture(),
+        String::from_utf8(self.source_body().await?).map_err(|e| {
+            let valid = e.utf8_error().valid_up_to();
+            let text = String::from_utf8_lossy(&e.as_bytes()[..valid]);
+            Error::syntax(
+                "source is not valid UTF-8",
+                Some(CodeFrame::new(self.meta.source(), &text, valid)),
+            )
         })
     }
     /// Create target file's parent directory
     #[tracing::instrument(skip(self))]
     pub async fn create_target_parent_dir(&self) -> Result<PathBuf, Error> {
         if let Some(target) = self.target().await {
-            let dir = target.parent().unwrap();
-            fs::create_dir_all(dir).map_err(|io_error| Error::FileIo {
-                trace: SpanTrace::capture(),
-                io_error,
-            })?;
+            let dir = target.parent().unwrap().to_owned();
+            self.with_file_system(move |fs| fs.create_dir_all(&dir))
+                .await?;
             Ok(target)
         } else {
-            Err(Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })
+            Err(Error::missing_metadata(TARGET_FILE_META))
         }
     }
-    /// Open target file to write
+    /// Open target file to write. This always opens the file on disk, ignoring [`FileSystem`].
+    #[deprecated(note = "use `Context::write_target`, which supports `FileSystem`")]
     #[tracing::instrument(skip(self))]
     pub async fn open_target(&self) -> Result<fs::File, Error> {
         let target = self.create_target_parent_dir().await?;
@@ -141,4 +285,122 @@ impl Context {
             io_error,
         })
     }
+    /// Write data to target file, and record it
+    #[tracing::instrument(skip(self, data))]
+    pub async fn write_target(&self, data: impl Into<Bytes>) -> Result<PathBuf, Error> {
+        let target = self
+            .target()
+            .await
+            .ok_or_else(|| Error::missing_metadata(TARGET_FILE_META))?;
+        let data = data.into();
+        let bytes = data.len() as u64;
+        let path = target.clone();
+        self.with_file_system(move |fs| {
+            fs.create_dir_all(path.parent().unwrap())?;
+            fs.write(&path, &data)
+        })
+        .await?;
+        self.record_write(&target, bytes);
+        Ok(target)
+    }
+    /// Queue an additional output file of the compilation task, such as `post.json` beside
+    /// `post.html`, and returns its target file path.
+    ///
+    /// The path is the URL path of the file, such as `/posts/a.json`, which is placed in the
+    /// target directory. The file is written when the current step finishes, recorded in
+    /// [`BuildReport`], and added to [`OUTPUTS_META`] as `{target, path}`.
+    /// Paths with `..` are rejected, so that files are not written outside the target directory.
+    pub fn emit(&self, path: impl AsRef<Path>, body: impl Into<Bytes>) -> Result<PathBuf, Error> {
+        let path = path.as_ref();
+        let mut relative = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(c) => relative.push(c),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(Error::FileIo {
+                        trace: SpanTrace::capture(),
+                        io_error: io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "output path `{}` is outside the target directory",
+                                path.display()
+                            ),
+                        ),
+                    });
+                }
+            }
+        }
+        let target = self.config.target_dir().join(&relative);
+        self.emitted.lock().unwrap().push(Emitted {
+            target: target.clone(),
+            path: Path::new("/").join(relative),
+            body: body.into(),
+        });
+        Ok(target)
+    }
+    /// Queue an additional output file whose path is the current URL path with the extension,
+    /// such as `/posts/a.json` for `/posts/a.html`. See [`Context::emit`].
+    pub fn emit_with_extension(
+        &self,
+        extension: impl AsRef<str>,
+        body: impl Into<Bytes>,
+    ) -> Result<PathBuf, Error> {
+        let path = self
+            .meta
+            .path()
+            .ok_or_else(|| Error::missing_metadata(PATH_META))?;
+        self.emit(path.with_extension(extension.as_ref()), body)
+    }
+    /// Add the output file to [`OUTPUTS_META`]
+    pub(crate) fn add_output(&mut self, target: &Path, path: &Path) {
+        let output = serde_json::json!({
+            "target": target.to_string_lossy(),
+            "path": path.to_string_lossy(),
+        });
+        let mut outputs = match self.meta.local().get(OUTPUTS_META) {
+            Some(Value::Array(outputs)) => outputs.clone(),
+            _ => Vec::new(),
+        };
+        outputs.push(output);
+        self.meta
+            .insert_local(OUTPUTS_META.to_owned(), Value::Array(outputs));
+    }
+    /// Write the files queued by [`Context::emit`]
+    pub(crate) async fn write_emitted(&mut self) -> Result<(), Error> {
+        let emitted = std::mem::take(&mut *self.emitted.lock().unwrap());
+        for file in emitted {
+            let bytes = file.body.len() as u64;
+            let target = file.target.clone();
+            self.with_file_system(move |fs| {
+                fs.create_dir_all(target.parent().unwrap())?;
+                fs.write(&target, &file.body)
+            })
+            .await?;
+            self.record_write(&file.target, bytes);
+            self.add_output(&file.target, &file.path);
+        }
+        Ok(())
+    }
+    /// Copy source file to target file, and record it
+    #[tracing::instrument(skip(self))]
+    pub async fn copy_source_to_target(&self) -> Result<PathBuf, Error> {
+        let source = self
+            .source()
+            .await
+            .ok_or_else(|| Error::missing_metadata(SOURCE_FILE_META))?;
+        let target = self
+            .target()
+            .await
+            .ok_or_else(|| Error::missing_metadata(TARGET_FILE_META))?;
+        let path = target.clone();
+        let bytes = self
+            .with_file_system(move |fs| {
+                fs.create_dir_all(path.parent().unwrap())?;
+                fs.copy(&source, &path)
+            })
+            .await?;
+        self.record_write(&target, bytes);
+        Ok(target)
+    }
 }
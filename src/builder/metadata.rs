@@ -1,5 +1,10 @@
 use crate::{error::Error, *};
-use serde::{ser::SerializeMap, Serialize};
+use bytes::Bytes;
+use serde::{
+    de::{self, DeserializeOwned},
+    ser::SerializeMap,
+    Serialize,
+};
 use serde_json::{json, to_value, Map, Number};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -15,13 +20,29 @@ pub const TARGET_FILE_META: &str = "_target";
 pub const PATH_META: &str = "_path";
 pub const VERSION_META: &str = "_version";
 pub const BODY_META: &str = "_body";
+/// Line number in the source file at which the body in [`BODY_META`] begins, which is inserted
+/// when front matter is stripped from the body.
+pub const BODY_LINE_META: &str = "_body_line";
 pub const VERSIONS_META: &str = "_versions";
+pub const LANG_META: &str = "_lang";
+pub const TRANSLATION_KEY_META: &str = "_translation_key";
+/// Additional output files of the compilation task, which is an array of `{target, path}`.
+/// See [`Context::emit`][crate::Context::emit].
+pub const OUTPUTS_META: &str = "_outputs";
+/// Key of the marker object which is inserted to local metadata in place of binary data,
+/// such as `{"_bytes": 1024}`.
+pub const BYTES_META: &str = "_bytes";
 
 /// [`Metadata`] holds global and local metadata, which is represented as a [`Value`].
+///
+/// Binary data is held out-of-band as [`Bytes`], and is inserted by
+/// [`Metadata::insert_local_bytes`]. Binary data is not published to global metadata, and is not
+/// passed to templates.
 #[derive(Clone, Debug)]
 pub struct Metadata {
     global: Arc<RwLock<Value>>,
     local: Value,
+    bytes: HashMap<String, Bytes>,
 }
 
 /// Read locked metadata, which contains locked global metadata.
@@ -31,6 +52,12 @@ pub struct ReadLockedMetadata<'a> {
     locked: Arc<RwLockReadGuard<'a, Value>>,
 }
 
+impl Default for Metadata {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Metadata {
     pub fn new() -> Self {
         Self {
@@ -38,9 +65,20 @@ impl Metadata {
                 VERSIONS_META: json!({}),
             }))),
             local: json!({}),
+            bytes: HashMap::new(),
         }
     }
-    pub async fn read_lock(&self) -> ReadLockedMetadata {
+    /// Create new metadata with initial global metadata
+    pub(crate) fn with_global(global: Map<String, Value>) -> Self {
+        let mut initial = global;
+        initial.insert(VERSIONS_META.to_owned(), json!({}));
+        Self {
+            global: Arc::new(RwLock::new(Value::Object(initial))),
+            local: json!({}),
+            bytes: HashMap::new(),
+        }
+    }
+    pub async fn read_lock(&self) -> ReadLockedMetadata<'_> {
         ReadLockedMetadata {
             metadata: self,
             locked: Arc::new(self.global.read().await),
@@ -49,10 +87,10 @@ impl Metadata {
     pub fn local(&self) -> &Map<String, Value> {
         self.local.as_object().unwrap()
     }
-    pub async fn global(&self) -> RwLockReadGuard<Map<String, Value>> {
+    pub async fn global(&self) -> RwLockReadGuard<'_, Map<String, Value>> {
         RwLockReadGuard::map(self.global.read().await, |v| v.as_object().unwrap())
     }
-    pub async fn global_mut(&self) -> RwLockMappedWriteGuard<Map<String, Value>> {
+    pub async fn global_mut(&self) -> RwLockMappedWriteGuard<'_, Map<String, Value>> {
         RwLockWriteGuard::map(self.global.write().await, |v| v.as_object_mut().unwrap())
     }
     pub async fn get(&self, key: &str) -> Option<Value> {
@@ -62,6 +100,38 @@ impl Metadata {
             self.global.read().await.get(key).cloned()
         }
     }
+    /// Get metadata deserialized as `T`. If the key is missing, `null` is deserialized, so that
+    /// [`Option`] can be used for optional metadata.
+    pub async fn get_as<T: DeserializeOwned>(&self, key: &str) -> Result<T, Error> {
+        match self.get(key).await {
+            Some(value) => from_value(key, value),
+            None => serde_json::from_value(Value::Null).map_err(|_| Error::missing_metadata(key)),
+        }
+    }
+    /// Insert serializable data to local metadata
+    pub fn insert_local_as(
+        &mut self,
+        key: impl AsRef<str>,
+        data: impl Serialize,
+    ) -> Result<(), Error> {
+        let value = Self::to_value(data)?;
+        self.insert_local(key.as_ref().to_owned(), value);
+        Ok(())
+    }
+    /// Insert serializable data to global metadata
+    pub async fn insert_global_as(
+        &self,
+        key: impl AsRef<str>,
+        data: impl Serialize,
+    ) -> Result<(), Error> {
+        let value = Self::to_value(data)?;
+        self.insert_global(key.as_ref().to_owned(), value).await;
+        Ok(())
+    }
+    /// Deserialize all local metadata, except binary data, as `T`
+    pub fn local_as<T: DeserializeOwned>(&self) -> Result<T, Error> {
+        from_value("", Value::Object(self.published_local()))
+    }
     pub async fn insert_global(&self, key: String, metadata: Value) {
         self.global
             .write()
@@ -71,8 +141,35 @@ impl Metadata {
             .insert(key, metadata);
     }
     pub fn insert_local(&mut self, key: String, metadata: Value) {
+        self.bytes.remove(&key);
         self.local.as_object_mut().unwrap().insert(key, metadata);
     }
+    /// Remove local metadata, including binary data
+    pub fn remove_local(&mut self, key: &str) -> Option<Value> {
+        self.bytes.remove(key);
+        self.local.as_object_mut().unwrap().remove(key)
+    }
+    /// Insert binary data to local metadata.
+    /// The data is held out-of-band, and a marker object like `{"_bytes": 1024}` is inserted
+    /// instead.
+    pub fn insert_local_bytes(&mut self, key: String, bytes: impl Into<Bytes>) {
+        let bytes = bytes.into();
+        self.local
+            .as_object_mut()
+            .unwrap()
+            .insert(key.clone(), json!({ BYTES_META: bytes.len() }));
+        self.bytes.insert(key, bytes);
+    }
+    /// Get binary data of local metadata
+    pub fn get_bytes(&self, key: &str) -> Option<&Bytes> {
+        self.bytes.get(key)
+    }
+    /// Get local metadata without the keys of binary data, which is published to global metadata.
+    pub(crate) fn published_local(&self) -> Map<String, Value> {
+        let mut local = self.local().clone();
+        local.retain(|k, _| !self.bytes.contains_key(k));
+        local
+    }
     #[tracing::instrument(skip(ser))]
     pub fn to_value(ser: impl Serialize) -> Result<Value, Error> {
         to_value(ser).map_err(|serde_error| Error::SerdeJson {
@@ -81,50 +178,62 @@ impl Metadata {
         })
     }
     pub fn merge(&mut self, other: Metadata) {
+        for key in other.local().keys() {
+            self.bytes.remove(key);
+        }
         merge_values(&mut self.local, other.local);
+        self.bytes.extend(other.bytes);
     }
 
     /// Get currently compiling [`Version`]
     pub fn version(&self) -> Option<Version> {
         self.local
             .get(VERSION_META)
-            .map(|v| v.as_str().map(|v| v.into()))
-            .flatten()
+            .and_then(|v| v.as_str().map(|v| v.into()))
     }
 
     /// Get currently compiling rule name
     pub fn rule(&self) -> Option<String> {
         self.local
             .get(RULE_META)
-            .map(|v| v.as_str().map(|v| v.to_owned()))
-            .flatten()
+            .and_then(|v| v.as_str().map(|v| v.to_owned()))
     }
 
     /// Get currently compiling source file path
     pub fn source(&self) -> Option<PathBuf> {
         self.local
             .get(SOURCE_FILE_META)
-            .map(|v| v.as_str().map(|v| PathBuf::from(v)))
-            .flatten()
+            .and_then(|v| v.as_str().map(PathBuf::from))
     }
     /// Get currently compiling target file path
     pub fn target(&self) -> Option<PathBuf> {
         self.local
             .get(TARGET_FILE_META)
-            .map(|v| v.as_str().map(|v| PathBuf::from(v)))
-            .flatten()
+            .and_then(|v| v.as_str().map(PathBuf::from))
     }
     /// Get currently compiling URL path
     pub fn path(&self) -> Option<PathBuf> {
         self.local
             .get(PATH_META)
-            .map(|v| v.as_str().map(|v| PathBuf::from(v)))
-            .flatten()
+            .and_then(|v| v.as_str().map(PathBuf::from))
     }
-    /// Get currently compiling body [`Value`], which can be [`Vec<u8>`] or [`String`].
+    /// Get currently compiling body [`Value`].
+    /// If the body is binary, this returns the marker object. Use [`Metadata::body_bytes`] to
+    /// get binary body.
     pub fn body(&self) -> Option<&Value> {
         self.local.get(BODY_META)
     }
+    /// Get currently compiling body as bytes, which may be binary data or a string.
+    pub fn body_bytes(&self) -> Option<Bytes> {
+        match self.bytes.get(BODY_META) {
+            Some(bytes) => Some(bytes.clone()),
+            None => self
+                .local
+                .get(BODY_META)
+                .and_then(|v| v.as_str())
+                .map(|s| Bytes::copy_from_slice(s.as_bytes())),
+        }
+    }
 }
 impl ReadLockedMetadata<'_> {
     pub fn get_version(&self, version: &Version) -> Option<HashMap<String, Metadata>> {
@@ -132,8 +241,7 @@ impl ReadLockedMetadata<'_> {
             .get(VERSIONS_META)
             .unwrap()
             .get(version.get())
-            .map(|w| w.as_object())
-            .flatten()
+            .and_then(|w| w.as_object())
             .map(|v| {
                 HashMap::from_iter(v.iter().map(|(path, w)| {
                     (
@@ -141,13 +249,177 @@ impl ReadLockedMetadata<'_> {
                         Metadata {
                             global: self.metadata.global.clone(),
                             local: w.clone(),
+                            bytes: HashMap::new(),
                         },
                     )
                 }))
             })
     }
     pub fn metadata(&self) -> &Metadata {
-        &self.metadata
+        self.metadata
+    }
+}
+
+/// Deserialize metadata of the key as `T`. The error names the key, including the path in the
+/// value, and the expected type.
+pub(crate) fn from_value<T: DeserializeOwned>(key: &str, value: Value) -> Result<T, Error> {
+    serde_path_to_error::deserialize(ValueDeserializer::new(value)).map_err(|e| {
+        let mut path = key.to_owned();
+        let mut push = |segment: &str| {
+            if !path.is_empty() && !segment.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(segment);
+        };
+        let inner = e.path().to_string();
+        if inner != "." {
+            push(&inner);
+        }
+        let expected = match e.into_inner() {
+            DeError::MissingField(field) => {
+                push(field);
+                return Error::missing_metadata(path);
+            }
+            DeError::Invalid { expected, .. } => expected,
+            DeError::Custom(message) => format!("{} ({})", std::any::type_name::<T>(), message),
+        };
+        if path.is_empty() {
+            path = std::any::type_name::<T>().to_owned();
+        }
+        Error::metadata_type(path, expected)
+    })
+}
+
+/// Error of [`ValueDeserializer`], which keeps the kind of the error reported by `Deserialize`
+#[derive(Debug)]
+enum DeError {
+    MissingField(&'static str),
+    Invalid { expected: String, message: String },
+    Custom(String),
+}
+impl std::fmt::Display for DeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingField(field) => write!(f, "missing field `{}`", field),
+            Self::Invalid { message, .. } | Self::Custom(message) => f.write_str(message),
+        }
+    }
+}
+impl std::error::Error for DeError {}
+impl de::Error for DeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::Custom(msg.to_string())
+    }
+    fn missing_field(field: &'static str) -> Self {
+        Self::MissingField(field)
+    }
+    fn invalid_type(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        Self::Invalid {
+            expected: exp.to_string(),
+            message: format!("invalid type: {}, expected {}", unexp, exp),
+        }
+    }
+    fn invalid_value(unexp: de::Unexpected, exp: &dyn de::Expected) -> Self {
+        Self::Invalid {
+            expected: exp.to_string(),
+            message: format!("invalid value: {}, expected {}", unexp, exp),
+        }
+    }
+    fn invalid_length(len: usize, exp: &dyn de::Expected) -> Self {
+        Self::Invalid {
+            expected: exp.to_string(),
+            message: format!("invalid length {}, expected {}", len, exp),
+        }
+    }
+}
+
+/// Deserializer of [`Value`] with [`DeError`], because [`serde_json::Error`] does not tell a
+/// missing field from a value of the wrong type.
+struct ValueDeserializer(Value);
+impl ValueDeserializer {
+    fn new(value: Value) -> Self {
+        Self(value)
+    }
+    fn map(map: Map<String, Value>) -> de::value::MapDeserializer<'static, MapIter, DeError> {
+        de::value::MapDeserializer::new(map.into_iter().map(|(k, v)| (k, Self::new(v))))
+    }
+}
+type MapIter =
+    std::iter::Map<serde_json::map::IntoIter, fn((String, Value)) -> (String, ValueDeserializer)>;
+impl<'de> de::IntoDeserializer<'de, DeError> for ValueDeserializer {
+    type Deserializer = Self;
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = DeError;
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            Value::Null => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Number(n) => match (n.as_u64(), n.as_i64(), n.as_f64()) {
+                (Some(u), _, _) => visitor.visit_u64(u),
+                (_, Some(i), _) => visitor.visit_i64(i),
+                (_, _, Some(f)) => visitor.visit_f64(f),
+                _ => Err(de::Error::custom(format!("invalid number {}", n))),
+            },
+            Value::String(s) => visitor.visit_string(s),
+            Value::Array(arr) => {
+                let mut seq = de::value::SeqDeserializer::new(arr.into_iter().map(Self::new));
+                let value = visitor.visit_seq(&mut seq)?;
+                seq.end()?;
+                Ok(value)
+            }
+            Value::Object(map) => {
+                let mut map = Self::map(map);
+                let value = visitor.visit_map(&mut map)?;
+                map.end()?;
+                Ok(value)
+            }
+        }
+    }
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self.0 {
+            Value::String(s) => visitor.visit_enum(de::IntoDeserializer::into_deserializer(s)),
+            Value::Object(map) if map.len() == 1 => {
+                visitor.visit_enum(de::value::MapAccessDeserializer::new(Self::map(map)))
+            }
+            other => Err(de::Error::invalid_type(unexpected(&other), &"an enum")),
+        }
+    }
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf unit
+        unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+fn unexpected(value: &Value) -> de::Unexpected<'_> {
+    match value {
+        Value::Null => de::Unexpected::Unit,
+        Value::Bool(b) => de::Unexpected::Bool(*b),
+        Value::Number(_) => de::Unexpected::Other("number"),
+        Value::String(s) => de::Unexpected::Str(s),
+        Value::Array(_) => de::Unexpected::Seq,
+        Value::Object(_) => de::Unexpected::Map,
     }
 }
 
@@ -156,10 +428,8 @@ pub fn merge_values(one: &mut Value, other: Value) {
         (Value::Object(map), Value::Object(other)) => {
             for (key, val) in other.into_iter() {
                 if let Some(left) = map.get_mut(&key) {
-                    if left.is_object() && val.is_object() {
-                        merge_values(left, val);
-                        continue;
-                    } else if left.is_array() && val.is_array() {
+                    if (left.is_object() && val.is_object()) || (left.is_array() && val.is_array())
+                    {
                         merge_values(left, val);
                         continue;
                     }
@@ -186,7 +456,11 @@ impl Serialize for ReadLockedMetadata<'_> {
             iter.insert(k, v);
         }
         for (k, v) in self.metadata.local.as_object().unwrap().iter() {
-            iter.insert(k, v);
+            if self.metadata.bytes.contains_key(k) {
+                iter.remove(k);
+            } else {
+                iter.insert(k, v);
+            }
         }
         let mut map = serializer.serialize_map(Some(iter.len()))?;
         for (k, v) in iter.into_iter() {
@@ -197,6 +471,9 @@ impl Serialize for ReadLockedMetadata<'_> {
 }
 
 /// Trait for reading and writing binary data for [`Metadata`].
+///
+/// Binary data is encoded as an array of the data length and big-endian [`u64`] chunks.
+/// This encoding is large, so [`Metadata::insert_local_bytes`] should be preferred.
 pub trait BytesValue: Sized {
     fn from_ser(ser: impl Serialize) -> Result<Self, Error>;
     fn as_bytes(&self) -> Option<Vec<u8>>;
@@ -215,22 +492,15 @@ impl BytesValue for Value {
     fn as_bytes(&self) -> Option<Vec<u8>> {
         match self {
             Value::Array(array) => {
-                let data_size = match array.get(0).map(|v| v.as_u64()).flatten() {
-                    Some(v) => v,
-                    None => return None,
-                };
-                let byte_size = 8;
-                let remove = (data_size - (byte_size - (data_size % byte_size))) as usize;
-                let remain = &array[1..remove];
-                let mut res = Vec::new();
-                for data in remain {
-                    let data = match data.as_u64() {
-                        Some(v) => v,
-                        None => return None,
-                    };
-                    let bytes = data.to_be_bytes();
-                    res.extend_from_slice(bytes.as_slice());
+                let data_size = array.first().and_then(|v| v.as_u64())? as usize;
+                let mut res = Vec::with_capacity(data_size.next_multiple_of(8));
+                for data in &array[1..] {
+                    res.extend_from_slice(&data.as_u64()?.to_be_bytes());
+                }
+                if res.len() < data_size {
+                    return None;
                 }
+                res.truncate(data_size);
                 Some(res)
             }
             Value::String(string) => Some(string.as_bytes().to_vec()),
@@ -238,23 +508,103 @@ impl BytesValue for Value {
         }
     }
     fn from_bytes(bytes: &[u8]) -> Self {
-        let data_size = bytes.len();
-        let byte_size = 8;
-        let data_size = Value::Number(Number::from(data_size));
-        let mut array = vec![data_size];
-        let len = bytes.len() / byte_size + 1;
-        for i in 0..len {
-            let slice = &bytes[i * byte_size..];
+        let mut array = vec![Value::Number(Number::from(bytes.len()))];
+        for chunk in bytes.chunks(8) {
             let mut data = [0; 8];
-            for (i, n) in (0..).zip(&slice[..byte_size]) {
-                data[i] = *n;
-            }
-            let num = u64::from_be_bytes(data);
-            let num = Number::from(num);
-            let val = Value::Number(num);
-            array.push(val);
+            data[..chunk.len()].copy_from_slice(chunk);
+            array.push(Value::Number(Number::from(u64::from_be_bytes(data))));
         }
-        let res = Value::Array(array);
-        res
+        Value::Array(array)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_value_round_trip() {
+        for len in [0, 1, 7, 8, 9, 16, 100] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(Value::from_bytes(&data).as_bytes(), Some(data));
+        }
+    }
+
+    #[tokio::test]
+    async fn typed_metadata() {
+        #[derive(serde::Deserialize, Debug)]
+        struct Page {
+            title: String,
+            weight: Option<u32>,
+            tags: Vec<String>,
+        }
+        let mut meta = Metadata::new();
+        meta.insert_local_as("title", "hello").unwrap();
+        meta.insert_local_as("tags", ["a", "b"]).unwrap();
+        meta.insert_global_as("count", 3).await.unwrap();
+        assert_eq!(meta.get_as::<u32>("count").await.unwrap(), 3);
+        assert_eq!(meta.get_as::<Option<u32>>("weight").await.unwrap(), None);
+        let page: Page = meta.local_as().unwrap();
+        assert_eq!(page.title, "hello");
+        assert_eq!(page.tags, ["a", "b"]);
+        assert!(page.weight.is_none());
+
+        let error = meta.get_as::<u32>("title").await.unwrap_err();
+        assert!(
+            matches!(error, Error::MetadataType { ref key, ref expected, .. }
+            if key == "title" && expected == "u32")
+        );
+        let error = meta.get_as::<String>("missing").await.unwrap_err();
+        assert!(matches!(error, Error::MissingMetadata { ref key, .. } if key == "missing"));
+        meta.insert_local_as("tags", [1, 2]).unwrap();
+        let error = meta.local_as::<Page>().unwrap_err();
+        assert!(
+            matches!(error, Error::MetadataType { ref key, ref expected, .. }
+            if key == "tags[0]" && expected == "a string")
+        );
+        meta.insert_local("title".to_owned(), Value::Null);
+        meta.insert_local_as("tags", ["a"]).unwrap();
+        let mut local = meta.local().clone();
+        local.remove("title");
+        let error = from_value::<Page>("", Value::Object(local)).unwrap_err();
+        assert!(matches!(error, Error::MissingMetadata { ref key, .. } if key == "title"));
+
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        enum Kind {
+            Post,
+            Link { url: String },
+        }
+        let kinds = json!(["post", {"link": {"url": "/"}}]);
+        let kinds: Vec<Kind> = from_value("kinds", kinds).unwrap();
+        assert_eq!(
+            kinds[1],
+            Kind::Link {
+                url: "/".to_owned()
+            }
+        );
+        let error = from_value::<Vec<Kind>>("kinds", json!([{"link": {}}])).unwrap_err();
+        assert!(
+            matches!(error, Error::MissingMetadata { ref key, .. } if key == "kinds[0].link.url")
+        );
+        // messages which look like other errors are not misread
+        let error = from_value::<Kind>("kind", json!("missing field `x`")).unwrap_err();
+        assert!(matches!(error, Error::MetadataType { ref key, .. } if key == "kind"));
+    }
+
+    #[tokio::test]
+    async fn bytes_out_of_band() {
+        let mut meta = Metadata::new();
+        meta.insert_local("title".to_owned(), Value::from("image"));
+        meta.insert_local_bytes(BODY_META.to_owned(), vec![0u8, 159, 146, 150]);
+        assert_eq!(meta.body_bytes().unwrap().as_ref(), &[0, 159, 146, 150]);
+        assert_eq!(meta.body().unwrap()[BYTES_META], 4);
+        assert!(!meta.published_local().contains_key(BODY_META));
+        let ser = serde_json::to_value(meta.read_lock().await).unwrap();
+        assert!(ser.get(BODY_META).is_none());
+        assert_eq!(ser["title"], "image");
+        meta.insert_local(BODY_META.to_owned(), Value::from("text"));
+        assert!(meta.get_bytes(BODY_META).is_none());
+        assert_eq!(meta.body_bytes().unwrap().as_ref(), b"text");
     }
 }
@@ -0,0 +1,114 @@
+use super::report::*;
+use crate::*;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::PathBuf;
+
+/// Result of [`Builder::plan`], which shows how source files are matched to rules, without
+/// running compilers.
+///
+/// The targets and paths are the initial ones, before compilers such as
+/// [`SetExtension`][crate::compiler::path::SetExtension] change them.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct BuildPlan {
+    /// Compilation tasks in build order
+    pub tasks: Vec<PlannedTask>,
+    /// Sources skipped because they were already claimed with the same [`Version`], or they
+    /// are drafts, scheduled or expired pages
+    pub skipped: Vec<SkippedTask>,
+    /// Files in the source directory which no rule matched
+    pub unmatched: Vec<PathBuf>,
+    /// Rules which have no compilation task
+    pub unused_rules: Vec<UnusedRule>,
+}
+
+/// Planned compilation task
+#[derive(Serialize, Debug, Clone)]
+pub struct PlannedTask {
+    /// Index of the build step
+    pub step: usize,
+    pub rule: String,
+    pub version: String,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub path: PathBuf,
+}
+
+/// Rule which has no compilation task
+#[derive(Serialize, Debug, Clone)]
+pub struct UnusedRule {
+    /// Index of the build step
+    pub step: usize,
+    pub rule: String,
+    /// Number of matched sources. If not zero, all of them were claimed by other rules.
+    pub matched: usize,
+}
+
+impl BuildPlan {
+    /// Check whether the plan has no unmatched sources and no unused rules
+    pub fn is_clean(&self) -> bool {
+        self.unmatched.is_empty() && self.unused_rules.is_empty()
+    }
+}
+
+impl fmt::Display for BuildPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for task in &self.tasks {
+            writeln!(
+                f,
+                "[{}] {} -> {} ({}) -> {} ({})",
+                task.step,
+                task.source.display(),
+                task.rule,
+                task.version,
+                task.target.display(),
+                task.path.display(),
+            )?;
+        }
+        for skipped in &self.skipped {
+            writeln!(
+                f,
+                "skipped: {} in rule `{}`, {}",
+                skipped.source.display(),
+                skipped.rule,
+                skipped.reason
+            )?;
+        }
+        for source in &self.unmatched {
+            writeln!(f, "unmatched: {}", source.display())?;
+        }
+        for rule in &self.unused_rules {
+            if rule.matched == 0 {
+                writeln!(f, "unused: rule `{}` matched nothing", rule.rule)?;
+            } else {
+                writeln!(
+                    f,
+                    "unused: rule `{}` matched {} sources, all claimed by other rules",
+                    rule.rule, rule.matched
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sources matched by one [`Rule`]
+#[derive(Debug, Clone)]
+pub(crate) struct RulePlan {
+    pub step: usize,
+    pub matched: Vec<PathBuf>,
+    pub tasks: Vec<PlannedTask>,
+    pub skipped: Vec<SkippedTask>,
+}
+
+/// Sources claimed by each [`Version`]. The first rule to claim a source wins.
+#[derive(Debug, Default)]
+pub(crate) struct Claims(HashMap<Version, HashSet<PathBuf>>);
+
+impl Claims {
+    /// Claim the source. Returns `false` if the source is already claimed.
+    pub fn claim(&mut self, version: &Version, source: PathBuf) -> bool {
+        self.0.entry(version.clone()).or_default().insert(source)
+    }
+}
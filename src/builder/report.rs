@@ -0,0 +1,250 @@
+use crate::*;
+use serde::{Serialize, Serializer};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_error::SpanTrace;
+
+/// Result of [`Builder::build`], which lists rules, and compiled, failed and skipped files.
+///
+/// Times are measured from the start of the build. The report can be exported as JSON by
+/// [`BuildReport::to_json`], or as Chrome trace-event format by [`BuildReport::to_chrome_trace`],
+/// which can be loaded by `chrome://tracing` or Perfetto.
+#[derive(Serialize, Debug, Default)]
+pub struct BuildReport {
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+    pub rules: Vec<RuleReport>,
+    pub compiled: Vec<CompiledTask>,
+    pub failed: Vec<FailedTask>,
+    pub skipped: Vec<SkippedTask>,
+}
+
+impl BuildReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check whether all tasks succeeded
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Get the total bytes written
+    pub fn bytes_written(&self) -> u64 {
+        self.compiled
+            .iter()
+            .flat_map(|c| &c.written)
+            .map(|w| w.bytes)
+            .sum()
+    }
+
+    /// Export this report as JSON
+    pub fn to_json(&self) -> Result<String, Error> {
+        serde_json::to_string_pretty(self).map_err(|serde_error| Error::SerdeJson {
+            trace: SpanTrace::capture(),
+            serde_error,
+        })
+    }
+
+    /// Export this report as Chrome trace-event format.
+    /// Rules are shown in the first thread, and each compilation task is shown in its own thread
+    /// with its stages.
+    pub fn to_chrome_trace(&self) -> Value {
+        let mut events = vec![json!({
+            "name": "thread_name", "ph": "M", "pid": 1, "tid": 0,
+            "args": { "name": "rules" },
+        })];
+        for rule in &self.rules {
+            events.push(json!({
+                "name": rule.name,
+                "cat": "rule",
+                "ph": "X",
+                "pid": 1,
+                "tid": 0,
+                "ts": rule.start.as_micros() as u64,
+                "dur": rule.duration.as_micros() as u64,
+                "args": { "step": rule.step, "version": rule.version, "matched": rule.matched.len() },
+            }));
+        }
+        for (i, task) in self.compiled.iter().enumerate() {
+            let tid = i + 1;
+            events.push(json!({
+                "name": "thread_name", "ph": "M", "pid": 1, "tid": tid,
+                "args": { "name": task.source.display().to_string() },
+            }));
+            events.push(json!({
+                "name": task.rule,
+                "cat": "task",
+                "ph": "X",
+                "pid": 1,
+                "tid": tid,
+                "ts": task.start.as_micros() as u64,
+                "dur": task.duration.as_micros() as u64,
+                "args": { "source": task.source, "target": task.target, "written": task.written },
+            }));
+            for stage in &task.stages {
+                if !stage.queue.is_zero() {
+                    events.push(json!({
+                        "name": "queue",
+                        "cat": "queue",
+                        "ph": "X",
+                        "pid": 1,
+                        "tid": tid,
+                        "ts": (stage.start - stage.queue).as_micros() as u64,
+                        "dur": stage.queue.as_micros() as u64,
+                    }));
+                }
+                events.push(json!({
+                    "name": format!("stage {}", stage.index),
+                    "cat": "stage",
+                    "ph": "X",
+                    "pid": 1,
+                    "tid": tid,
+                    "ts": stage.start.as_micros() as u64,
+                    "dur": stage.duration.as_micros() as u64,
+                }));
+                if !stage.wait.is_zero() {
+                    events.push(json!({
+                        "name": "wait",
+                        "cat": "wait",
+                        "ph": "X",
+                        "pid": 1,
+                        "tid": tid,
+                        "ts": (stage.start + stage.duration).as_micros() as u64,
+                        "dur": stage.wait.as_micros() as u64,
+                    }));
+                }
+            }
+        }
+        json!({ "traceEvents": events, "displayTimeUnit": "ms" })
+    }
+
+    /// Write Chrome trace-event file
+    pub fn write_chrome_trace(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        let trace = serde_json::to_vec(&self.to_chrome_trace()).map_err(|serde_error| {
+            Error::SerdeJson {
+                trace: SpanTrace::capture(),
+                serde_error,
+            }
+        })?;
+        fs::write(path, trace).map_err(|io_error| Error::FileIo {
+            trace: SpanTrace::capture(),
+            io_error,
+        })
+    }
+
+    pub(crate) fn merge(&mut self, other: BuildReport) {
+        self.rules.extend(other.rules);
+        self.compiled.extend(other.compiled);
+        self.failed.extend(other.failed);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+/// Report of a [`Rule`]
+#[derive(Serialize, Debug, Clone)]
+pub struct RuleReport {
+    pub name: String,
+    pub version: String,
+    /// Index of the build step
+    pub step: usize,
+    /// Source files matched, including skipped files
+    pub matched: Vec<PathBuf>,
+    #[serde(rename = "start_ms", serialize_with = "serialize_millis")]
+    pub start: Duration,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+}
+
+/// Successfully compiled task
+#[derive(Serialize, Debug, Clone)]
+pub struct CompiledTask {
+    pub rule: String,
+    pub version: String,
+    pub source: PathBuf,
+    pub target: Option<PathBuf>,
+    /// Files written by the task
+    pub written: Vec<WrittenFile>,
+    #[serde(rename = "start_ms", serialize_with = "serialize_millis")]
+    pub start: Duration,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+    pub stages: Vec<StageTiming>,
+}
+
+/// File written by the compilation task
+#[derive(Serialize, Debug, Clone)]
+pub struct WrittenFile {
+    pub path: PathBuf,
+    pub bytes: u64,
+}
+
+/// Timing of one [`Compiler::next_step`] call of the compilation task
+#[derive(Serialize, Debug, Clone)]
+pub struct StageTiming {
+    pub index: usize,
+    #[serde(rename = "start_ms", serialize_with = "serialize_millis")]
+    pub start: Duration,
+    /// Time waiting for the concurrency limit before this stage
+    #[serde(rename = "queue_ms", serialize_with = "serialize_millis")]
+    pub queue: Duration,
+    #[serde(rename = "duration_ms", serialize_with = "serialize_millis")]
+    pub duration: Duration,
+    /// Time waiting for other tasks after this stage
+    #[serde(rename = "wait_ms", serialize_with = "serialize_millis")]
+    pub wait: Duration,
+}
+
+/// Failed task. `source` is `None` if the rule itself failed, such as invalid glob pattern.
+#[derive(Serialize, Debug)]
+pub struct FailedTask {
+    pub rule: String,
+    pub source: Option<PathBuf>,
+    #[serde(serialize_with = "serialize_error")]
+    pub error: Error,
+}
+
+/// Skipped task
+#[derive(Serialize, Debug, Clone)]
+pub struct SkippedTask {
+    pub rule: String,
+    pub source: PathBuf,
+    pub reason: SkipReason,
+}
+
+/// The reason why the task was skipped
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// The source file was already compiled with the same [`Version`].
+    Version,
+    /// The front matter has `draft: true`, and [`Config::drafts`] is not set.
+    Draft,
+    /// The front matter `date` is in the future, and [`Config::future`] is not set.
+    Future,
+    /// The front matter `expires` is in the past, and [`Config::expired`] is not set.
+    Expired,
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::Version => "already claimed",
+            Self::Draft => "draft",
+            Self::Future => "scheduled in the future",
+            Self::Expired => "expired",
+        };
+        f.write_str(reason)
+    }
+}
+
+fn serialize_millis<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+}
+
+fn serialize_error<S: Serializer>(error: &Error, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&error.report().color(false))
+}
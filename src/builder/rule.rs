@@ -1,9 +1,72 @@
-use super::compile::CompileRunner;
-use crate::*;
-use glob::glob;
-use std::path::PathBuf;
+use super::{
+    compile::{panic_error, CompileRunner},
+    plan::*,
+    report::*,
+};
+use crate::{
+    builder::metadata::*,
+    compiler::{date::value_to_date, markdown::parse_front_matter},
+    *,
+};
+use chrono::{DateTime, FixedOffset};
+use serde_json::Map;
+use std::collections::HashMap;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tracing_error::SpanTrace;
 
+/// Local metadata keys of each compilation task published to global metadata, which are
+/// `_versions.<version>.<source>` and the array of the rule name.
+///
+/// The default metadata, except [`BODY_META`], are always published.
+/// The default is [`Publish::Except`] `[_body]`, because bodies are large and rarely used by
+/// other tasks.
+#[derive(Clone, Debug)]
+pub enum Publish {
+    /// Publish all keys
+    All,
+    /// Publish only the specified keys
+    Only(Vec<String>),
+    /// Publish all keys except the specified keys
+    Except(Vec<String>),
+}
+impl Default for Publish {
+    fn default() -> Self {
+        Self::Except(vec![BODY_META.to_owned()])
+    }
+}
+
+impl Publish {
+    /// Create [`Publish::Only`]
+    pub fn only(keys: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self::Only(keys.into_iter().map(|k| k.as_ref().to_owned()).collect())
+    }
+    /// Create [`Publish::Except`]
+    pub fn except(keys: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self::Except(keys.into_iter().map(|k| k.as_ref().to_owned()).collect())
+    }
+
+    pub(crate) fn filter(&self, mut local: Map<String, Value>) -> Map<String, Value> {
+        const ALWAYS: [&str; 6] = [
+            RULE_META,
+            VERSION_META,
+            SOURCE_FILE_META,
+            TARGET_FILE_META,
+            PATH_META,
+            OUTPUTS_META,
+        ];
+        match self {
+            Self::All => {}
+            Self::Only(keys) => {
+                local.retain(|k, _| ALWAYS.contains(&k.as_str()) || keys.contains(k));
+            }
+            Self::Except(keys) => local.retain(|k, _| !keys.contains(k)),
+        }
+        local
+    }
+}
+
 /// The [`Rule`] is used to define the rule name, source files, [`Version`], and the [`Compiler`] used for building.
 /// The results of the compilation are saved in the [`Metadata`], using the rule's name as the key.
 pub struct Rule {
@@ -12,8 +75,15 @@ pub struct Rule {
     creates: Option<Vec<String>>,
     compiler: Box<dyn Compiler>,
     version: Version,
+    max_tasks: Option<usize>,
+    publish: Publish,
+    publication_checks: Option<bool>,
+    timeout: Option<Duration>,
 }
 
+/// Extensions of sources whose front matter is checked by default
+const PUBLICATION_EXTENSIONS: [&str; 4] = ["md", "markdown", "html", "htm"];
+
 impl Rule {
     pub fn new(name: impl AsRef<str>, compiler: impl Compiler + 'static) -> Self {
         let name = name.as_ref().to_owned();
@@ -23,6 +93,10 @@ impl Rule {
             creates: None,
             compiler: Box::new(compiler),
             version: Version::default(),
+            max_tasks: None,
+            publish: Publish::default(),
+            publication_checks: None,
+            timeout: None,
         }
     }
 
@@ -48,78 +122,314 @@ impl Rule {
         self
     }
 
-    /// Do compilation task
-    #[tracing::instrument(skip(self, ctx))]
-    pub(crate) async fn compile(self, ctx: Context) -> Result<Context, Error> {
-        let src_dir = ctx.config().source_dir();
-        let paths: Vec<_> = match (&self.globs, &self.creates) {
+    /// Limit the number of concurrently running compilation tasks of this rule, overriding
+    /// [`Config::max_tasks_per_rule`].
+    pub fn set_max_tasks(mut self, limit: usize) -> Self {
+        self.max_tasks = Some(limit.max(1));
+        self
+    }
+
+    /// Set the timeout of each step of the compilation tasks of this rule, overriding
+    /// [`Config::task_timeout`].
+    pub fn set_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set local metadata keys published to global metadata. The default drops [`BODY_META`].
+    pub fn set_publish(mut self, publish: Publish) -> Self {
+        self.publish = publish;
+        self
+    }
+
+    /// Check the front matter of sources before compilation, and skip drafts (`draft: true`),
+    /// scheduled pages (`date` in the future) and expired pages (`expires` in the past), unless
+    /// [`Config::drafts`], [`Config::future`] or [`Config::expired`] is set.
+    ///
+    /// By default, sources with `md`, `markdown`, `html` or `htm` extension are checked.
+    /// Skipped sources are still claimed, so later rules do not compile them.
+    pub fn set_publication_checks(mut self, checks: bool) -> Self {
+        self.publication_checks = Some(checks);
+        self
+    }
+
+    /// Names of [`Barrier`][crate::compiler::utils::Barrier]s which the compiler may wait at
+    pub(crate) fn barriers(&self) -> Vec<String> {
+        self.compiler.barriers()
+    }
+
+    /// Get compilation [`Version`]
+    pub fn get_version(&self) -> &Version {
+        &self.version
+    }
+
+    /// Find source files matched by this rule
+    pub(crate) fn sources(&self, config: &Config) -> Result<Vec<PathBuf>, Error> {
+        let src_dir = config.source_dir();
+        let fs = config.file_system();
+        match (&self.globs, &self.creates) {
             (Some(globs), None) => {
                 let globs = globs
                     .iter()
                     .map(|g| src_dir.join(PathBuf::from(g)).to_string_lossy().to_string());
-                let paths = globs
-                    .map(|g| glob(&g))
-                    .collect::<Result<Vec<_>, _>>()
-                    .map_err(|_| Error::InvalidRule {
-                        trace: SpanTrace::capture(),
-                    })?
-                    .into_iter()
-                    .map(|p| p.collect::<Result<Vec<_>, _>>())
+
+                Ok(globs
+                    .map(|g| fs.glob(&g))
                     .collect::<Result<Vec<_>, _>>()
-                    .map_err(|_| Error::InvalidRule {
+                    .map_err(|e| Error::InvalidRule {
                         trace: SpanTrace::capture(),
+                        message: format!("invalid glob in rule `{}`: {}", self.name, e),
                     })?
                     .into_iter()
                     .flatten()
-                    .filter(|p| p.is_file())
-                    .collect();
-                paths
-            }
-            (None, Some(paths)) => {
-                let paths = paths
-                    .iter()
-                    .map(|p| src_dir.join(PathBuf::from(p)))
-                    .collect();
-                paths
-            }
-            _ => {
-                return Err(Error::InvalidRule {
-                    trace: SpanTrace::capture(),
-                })
+                    .collect())
             }
+            (None, Some(paths)) => Ok(paths
+                .iter()
+                .map(|p| src_dir.join(PathBuf::from(p)))
+                .collect()),
+            _ => Err(Error::InvalidRule {
+                trace: SpanTrace::capture(),
+                message: format!(
+                    "rule `{}` must have either globs or create paths",
+                    self.name
+                ),
+            }),
+        }
+    }
+
+    /// Whether the front matter of the source is checked before compilation
+    fn publication_checked(&self, source: &Path) -> bool {
+        self.publication_checks.unwrap_or_else(|| {
+            source
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .is_some_and(|e| PUBLICATION_EXTENSIONS.contains(&e.as_str()))
+        })
+    }
+
+    /// Check the front matter of the source, and returns the reason to skip it
+    fn publication_skip(
+        config: &Config,
+        front_matter: &[u8],
+        now: DateTime<FixedOffset>,
+    ) -> Result<Option<SkipReason>, Error> {
+        // invalid front matter is reported by the compiler
+        let front_matter = match parse_front_matter(&String::from_utf8_lossy(front_matter), None) {
+            Ok((Some(front_matter), _)) => front_matter,
+            _ => return Ok(None),
         };
-        let version_files: Option<Vec<_>> = ctx
-            .metadata()
-            .read_lock()
-            .await
-            .get_version(&self.version)
-            .map(|v| v.into_iter().map(|(source, _)| source).collect());
-        let paths: Vec<_> = paths
-            .into_iter()
-            .filter(|p| {
-                version_files
-                    .as_ref()
-                    .map(|v| {
-                        v.iter()
-                            .filter(|w| &**w == &*p.to_string_lossy())
-                            .next()
-                            .is_none()
-                    })
-                    .unwrap_or(true)
-            })
-            .collect();
+        let tz = config.parse_timezone()?;
+        let date = |key| front_matter.get(key).and_then(|v| value_to_date(v, &tz));
+        if !config.drafts() && front_matter.get("draft") == Some(&Value::Bool(true)) {
+            return Ok(Some(SkipReason::Draft));
+        }
+        if !config.future() && date("date").is_some_and(|d| now < d) {
+            return Ok(Some(SkipReason::Future));
+        }
+        if !config.expired() && date("expires").is_some_and(|d| d <= now) {
+            return Ok(Some(SkipReason::Expired));
+        }
+        Ok(None)
+    }
 
-        let name = self.get_name().to_owned();
-        let src_dir = ctx.config().source_dir();
-        let target_dir = ctx.config().target_dir();
-        let runner = CompileRunner::new(name, self.version, ctx, self.compiler);
-        for source in paths {
+    /// Match source files, and claim them. Sources already claimed with the same [`Version`] are
+    /// skipped. The front matter is checked later by [`Rule::check_publication`].
+    pub(crate) fn plan(
+        &self,
+        config: &Config,
+        step: usize,
+        claims: &mut Claims,
+    ) -> Result<RulePlan, Error> {
+        let src_dir = config.source_dir();
+        let target_dir = config.target_dir();
+        let matched = self.sources(config)?;
+        let mut tasks = Vec::new();
+        let mut skipped = Vec::new();
+        for source in matched.iter().cloned() {
+            if !claims.claim(&self.version, source.clone()) {
+                skipped.push(SkippedTask {
+                    rule: self.name.clone(),
+                    source,
+                    reason: SkipReason::Version,
+                });
+                continue;
+            }
             let path = source.strip_prefix(&src_dir).unwrap_or(&source);
             let target = target_dir.join(path);
             let path = PathBuf::from("/").join(path);
-            runner.spawn_compile(source, target, path).await;
+            tasks.push(PlannedTask {
+                step,
+                rule: self.name.clone(),
+                version: self.version.get().to_owned(),
+                source,
+                target,
+                path,
+            });
+        }
+        Ok(RulePlan {
+            step,
+            matched,
+            tasks,
+            skipped,
+        })
+    }
+
+    /// Skip planned sources by [`Rule::set_publication_checks`]. Only the front matter of each
+    /// source is read on the blocking thread pool, limited by [`Config::io_concurrency`].
+    pub(crate) async fn check_publication(
+        &self,
+        ctx: &Context,
+        plan: RulePlan,
+    ) -> Result<RulePlan, Error> {
+        let config = ctx.config();
+        let now = chrono::Utc::now().fixed_offset();
+        let mut set = tokio::task::JoinSet::new();
+        for source in self.publication_sources(&plan) {
+            let ctx = ctx.clone();
+            set.spawn(async move {
+                let path = source.clone();
+                let front_matter = ctx
+                    .with_file_system(move |fs| read_front_matter(fs, &path))
+                    .await?;
+                Ok::<_, Error>((source, front_matter))
+            });
+        }
+        let mut reasons = HashMap::new();
+        while let Some(res) = set.join_next().await {
+            let (source, front_matter) = res.map_err(panic_error)??;
+            if let Some(front_matter) = front_matter {
+                if let Some(reason) = Self::publication_skip(&config, &front_matter, now)? {
+                    reasons.insert(source, reason);
+                }
+            }
+        }
+        Ok(self.skip_unpublished(plan, reasons))
+    }
+
+    /// Blocking version of [`Rule::check_publication`], used by [`Builder::plan`]
+    pub(crate) fn check_publication_blocking(
+        &self,
+        config: &Config,
+        plan: RulePlan,
+    ) -> Result<RulePlan, Error> {
+        let fs = config.file_system();
+        let now = chrono::Utc::now().fixed_offset();
+        let mut reasons = HashMap::new();
+        for source in self.publication_sources(&plan) {
+            let front_matter =
+                read_front_matter(fs.as_ref(), &source).map_err(|io_error| Error::FileIo {
+                    trace: SpanTrace::capture(),
+                    io_error,
+                })?;
+            if let Some(front_matter) = front_matter {
+                if let Some(reason) = Self::publication_skip(config, &front_matter, now)? {
+                    reasons.insert(source, reason);
+                }
+            }
+        }
+        Ok(self.skip_unpublished(plan, reasons))
+    }
+
+    fn publication_sources(&self, plan: &RulePlan) -> Vec<PathBuf> {
+        plan.tasks
+            .iter()
+            .filter(|t| self.publication_checked(&t.source))
+            .map(|t| t.source.clone())
+            .collect()
+    }
+
+    fn skip_unpublished(
+        &self,
+        mut plan: RulePlan,
+        mut reasons: HashMap<PathBuf, SkipReason>,
+    ) -> RulePlan {
+        let mut tasks = Vec::with_capacity(plan.tasks.len());
+        for task in plan.tasks {
+            match reasons.remove(&task.source) {
+                Some(reason) => plan.skipped.push(SkippedTask {
+                    rule: self.name.clone(),
+                    source: task.source,
+                    reason,
+                }),
+                None => tasks.push(task),
+            }
+        }
+        plan.tasks = tasks;
+        plan
+    }
+
+    /// Do compilation tasks planned by [`Rule::plan`]
+    #[tracing::instrument(skip(self, ctx, plan))]
+    pub(crate) async fn compile(self, ctx: Context, plan: RulePlan) -> Result<BuildReport, Error> {
+        let start = ctx.elapsed();
+        let name = self.get_name().to_owned();
+        let version = self.version.get().to_owned();
+        let max_tasks = self.max_tasks.or(ctx.config().max_tasks_per_rule());
+        let timeout = self.timeout.or(ctx.config().task_timeout());
+        let mut runner = CompileRunner::new(
+            plan.tasks.len(),
+            name.clone(),
+            self.version,
+            ctx.clone(),
+            self.compiler,
+            max_tasks,
+            self.publish,
+        )
+        .with_timeout(timeout);
+        for task in plan.tasks {
+            runner
+                .spawn_compile(task.source, task.target, task.path)
+                .await;
+        }
+        let mut report = runner.join().await?;
+        report.skipped.extend(plan.skipped);
+        report.rules.push(RuleReport {
+            name,
+            version,
+            step: plan.step,
+            matched: plan.matched,
+            start,
+            duration: ctx.elapsed() - start,
+        });
+        Ok(report)
+    }
+}
+
+/// Read the front matter of the source up to the closing `---` line. Returns [`None`] if the
+/// source is not a file or has no front matter.
+fn read_front_matter(fs: &dyn fs::FileSystem, source: &Path) -> io::Result<Option<Vec<u8>>> {
+    if !fs.is_file(source) {
+        return Ok(None);
+    }
+    let mut reader = fs.open(source)?;
+    let mut data = Vec::new();
+    reader.read_until(b'\n', &mut data)?;
+    if data.trim_ascii_end() != b"---" {
+        return Ok(None);
+    }
+    loop {
+        let start = data.len();
+        if reader.read_until(b'\n', &mut data)? == 0 || data[start..].trim_ascii_end() == b"---" {
+            return Ok(Some(data));
         }
-        let ctx = runner.join().await?;
-        Ok(ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn front_matter_only() {
+        let fs = fs::MemoryFileSystem::new()
+            .with_file("a.md", "---\ndraft: true\n---\nbody\n---\n")
+            .with_file("b.md", "body\n---\ndraft: true\n---\n")
+            .with_file("c.md", "---\r\ntitle: c\r\n---\r\nbody");
+        let read = |path: &str| read_front_matter(&fs, Path::new(path)).unwrap();
+        assert_eq!(read("a.md").unwrap(), b"---\ndraft: true\n---\n");
+        assert_eq!(read("b.md"), None);
+        assert_eq!(read("c.md").unwrap(), b"---\r\ntitle: c\r\n---\r\n");
+        assert_eq!(read("missing.md"), None);
     }
 }
@@ -1,7 +1,14 @@
+pub mod date;
+pub mod exec;
 pub mod file;
+pub mod i18n;
 pub mod markdown;
 pub mod metadata;
 pub mod path;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod search;
+pub mod shortcode;
 pub mod template;
 pub mod utils;
 
@@ -17,8 +24,32 @@ pub enum CompileStep {
     Completed(Context),
     /// Compilation task is in progress.
     InProgress(Context),
-    /// Wait for other tasks to finish the same step.
+    /// Wait for other tasks of the same rule to reach the same number of [`WaitStage`][utils::WaitStage]s.
     WaitStage(Context),
+    /// Wait for all tasks in the same build step which take part in the named barrier.
+    /// See [`Barrier`][utils::Barrier].
+    WaitBarrier(String, Context),
+}
+
+impl CompileStep {
+    /// Get the context of this step
+    pub fn context(&self) -> &Context {
+        match self {
+            Self::Completed(ctx)
+            | Self::InProgress(ctx)
+            | Self::WaitStage(ctx)
+            | Self::WaitBarrier(_, ctx) => ctx,
+        }
+    }
+    /// Get the mutable context of this step
+    pub fn context_mut(&mut self) -> &mut Context {
+        match self {
+            Self::Completed(ctx)
+            | Self::InProgress(ctx)
+            | Self::WaitStage(ctx)
+            | Self::WaitBarrier(_, ctx) => ctx,
+        }
+    }
 }
 
 /// [`CompileResult`] is the result type that returned by compiler's compile method.
@@ -27,12 +58,44 @@ pub type CompileResult = Result<CompileStep, Error>;
 pub type CompilerReturn = Pin<Box<dyn Future<Output = CompileResult> + Send>>;
 
 /// All compiler must implement [`Compiler`] trait.
-pub trait Compiler: DynClone + Send + Sync {
+///
+/// [`Rule`] holds one compiler, and each compilation task runs its own instance created by
+/// [`Compiler::fresh`], which is a clone of the compiler by default. Compilers which keep state
+/// across steps, such as [`PipeCompiler`][utils::PipeCompiler], override it to start from the
+/// first step, so that no state is shared between tasks.
+pub trait Compiler: DynClone + CloneCompiler + Send + Sync {
     /// Executes the next step of the compilation
     fn next_step(&mut self, ctx: Context) -> CompilerReturn;
+    /// Names of [`Barrier`][utils::Barrier]s which this compiler may wait at.
+    /// Compilers which contain other compilers should return the barriers of them.
+    fn barriers(&self) -> Vec<String> {
+        Vec::new()
+    }
+    /// Stage indices of the running compiler in nested [`PipeCompiler`][utils::PipeCompiler]s,
+    /// which are reported when the step times out.
+    /// Compilers which contain other compilers should return the stage of the running one.
+    fn stage(&self) -> Vec<usize> {
+        Vec::new()
+    }
+    /// Create the instance of this compiler for a new compilation task.
+    /// Compilers which contain other compilers should create fresh instances of them.
+    fn fresh(&self) -> Box<dyn Compiler> {
+        self.clone_compiler()
+    }
 }
 clone_trait_object!(Compiler);
 
+/// Boxed clone of the compiler, which is the default of [`Compiler::fresh`].
+/// This is implemented for all compilers.
+pub trait CloneCompiler {
+    fn clone_compiler(&self) -> Box<dyn Compiler>;
+}
+impl<T: Compiler + 'static> CloneCompiler for T {
+    fn clone_compiler(&self) -> Box<dyn Compiler> {
+        dyn_clone::clone_box(self)
+    }
+}
+
 /// [`compile!`] macro may used to make pinned, boxed Future, which is async block.
 #[macro_export]
 macro_rules! compile {
@@ -0,0 +1,236 @@
+use crate::{builder::metadata::*, *};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, TimeZone as _, Utc};
+use serde_json::json;
+use std::path::Path;
+use std::time::SystemTime;
+use tracing_error::SpanTrace;
+
+/// Time zone used for dates without offset, which is a fixed offset such as `+09:00`, `UTC`, or
+/// an IANA name such as `Asia/Tokyo`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeZone {
+    Fixed(FixedOffset),
+    Named(chrono_tz::Tz),
+}
+impl Default for TimeZone {
+    fn default() -> Self {
+        Self::Fixed(FixedOffset::east_opt(0).unwrap())
+    }
+}
+
+impl TimeZone {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "UTC" | "utc" | "Z" => Some(Self::default()),
+            _ => text
+                .parse::<FixedOffset>()
+                .map(Self::Fixed)
+                .ok()
+                .or_else(|| text.parse::<chrono_tz::Tz>().ok().map(Self::Named)),
+        }
+    }
+    /// Interpret the local date time in this time zone
+    pub fn localize(&self, naive: NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        match self {
+            Self::Fixed(offset) => offset.from_local_datetime(&naive).single(),
+            Self::Named(tz) => tz
+                .from_local_datetime(&naive)
+                .earliest()
+                .map(|d| d.fixed_offset()),
+        }
+    }
+    /// Convert the date time into this time zone
+    pub fn convert(&self, date: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+        match self {
+            Self::Fixed(offset) => date.with_timezone(offset),
+            Self::Named(tz) => date.with_timezone(tz).fixed_offset(),
+        }
+    }
+}
+
+/// Parse date in RFC 3339, `YYYY-MM-DD HH:MM:SS`, `YYYY-MM-DD HH:MM` or `YYYY-MM-DD`.
+/// `T` may be used instead of the space. Dates without offset are in the specified time zone.
+pub fn parse_date(text: &str, tz: &TimeZone) -> Option<DateTime<FixedOffset>> {
+    let text = text.trim();
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Some(date);
+    }
+    let formats = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ];
+    let naive = formats
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(text, f).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()
+                .and_then(|d| d.and_hms_opt(0, 0, 0))
+        })?;
+    tz.localize(naive)
+}
+
+/// Create normalized date value, which is `{iso, timestamp}`
+pub fn date_value(date: DateTime<FixedOffset>) -> Value {
+    json!({
+        "iso": date.to_rfc3339(),
+        "timestamp": date.timestamp(),
+    })
+}
+
+/// Read date from normalized date value, date string, or UNIX timestamp
+pub fn value_to_date(value: &Value, tz: &TimeZone) -> Option<DateTime<FixedOffset>> {
+    match value {
+        Value::Object(map) => map
+            .get("iso")
+            .and_then(|v| v.as_str())
+            .and_then(|iso| DateTime::parse_from_rfc3339(iso).ok()),
+        Value::String(text) => parse_date(text, tz),
+        Value::Number(n) => n
+            .as_i64()
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .map(|d| tz.convert(d.fixed_offset())),
+        _ => None,
+    }
+}
+
+/// The date used when the metadata has no date
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DateFallback {
+    /// Leave the date missing
+    #[default]
+    None,
+    /// Modification time of the source file
+    Mtime,
+    /// Last commit time of the source file in git, falling back to modification time
+    GitCommit,
+}
+
+/// [`ParseDate`] parses dates in local metadata, such as front matter `date`, and normalizes them
+/// into `{iso, timestamp}`.
+///
+/// Dates without offset are in [`Config::timezone`], which is UTC by default. The normalized dates can be sorted by
+/// [`Collection`][crate::builder::collection::Collection], and formatted by the `date` filter of
+/// [`TemplateEngine`][crate::compiler::template::TemplateEngine], such as
+/// `{{ date | date(format="%Y-%m-%d") }}`.
+#[derive(Clone)]
+pub struct ParseDate {
+    keys: Vec<String>,
+    fallback: DateFallback,
+}
+impl Default for ParseDate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParseDate {
+    /// Create new date parser for `date` key
+    pub fn new() -> Self {
+        Self {
+            keys: vec!["date".to_owned()],
+            fallback: DateFallback::None,
+        }
+    }
+    /// Set the metadata keys to parse
+    pub fn keys(mut self, keys: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.keys = keys.into_iter().map(|k| k.as_ref().to_owned()).collect();
+        self
+    }
+    /// Set the fallback used when the key is missing. The default is [`DateFallback::None`].
+    pub fn fallback(mut self, fallback: DateFallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+}
+
+async fn git_commit_time(source: &Path) -> Option<DateTime<FixedOffset>> {
+    let output = tokio::process::Command::new("git")
+        .args(["log", "-1", "--format=%cI", "--"])
+        .arg(source)
+        .output()
+        .await
+        .ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    DateTime::parse_from_rfc3339(text.trim()).ok()
+}
+
+async fn fallback_date(
+    ctx: &Context,
+    fallback: DateFallback,
+) -> Result<Option<DateTime<FixedOffset>>, Error> {
+    let source = match (fallback, ctx.source().await) {
+        (DateFallback::None, _) | (_, None) => return Ok(None),
+        (_, Some(source)) => source,
+    };
+    if fallback == DateFallback::GitCommit {
+        if let Some(date) = git_commit_time(&source).await {
+            return Ok(Some(date));
+        }
+    }
+    let modified: SystemTime = ctx.with_file_system(move |fs| fs.modified(&source)).await?;
+    Ok(Some(DateTime::<Utc>::from(modified).fixed_offset()))
+}
+
+impl Compiler for ParseDate {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let parser = self.clone();
+        compile!({
+            let tz = ctx.config().parse_timezone()?;
+            for key in parser.keys {
+                let date = match ctx.metadata().local().get(&key) {
+                    Some(value) if !value.is_null() => Some(
+                        value_to_date(value, &tz)
+                            .ok_or_else(|| Error::metadata_type(&key, "a date"))?,
+                    ),
+                    _ => fallback_date(&ctx, parser.fallback)
+                        .await?
+                        .map(|d| tz.convert(d)),
+                };
+                if let Some(date) = date {
+                    ctx.metadata_mut().insert_local(key, date_value(date));
+                }
+            }
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+/// Create [`Error::InvalidConfig`] for invalid time zone
+pub(crate) fn invalid_time_zone(text: &str) -> Error {
+    Error::InvalidConfig {
+        trace: SpanTrace::capture(),
+        message: format!("invalid time zone `{}`", text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dates() {
+        let tokyo = TimeZone::parse("Asia/Tokyo").unwrap();
+        let fixed = TimeZone::parse("+09:00").unwrap();
+        for text in [
+            "2024-01-19 02:53",
+            "2024-01-19T02:53:00",
+            "2024-01-19T02:53:00+09:00",
+        ] {
+            let date = parse_date(text, &tokyo).unwrap();
+            assert_eq!(date.to_rfc3339(), "2024-01-19T02:53:00+09:00");
+            assert_eq!(parse_date(text, &fixed), Some(date));
+        }
+        let date = parse_date("2024-01-19", &TimeZone::default()).unwrap();
+        let value = date_value(date);
+        assert_eq!(value["iso"], "2024-01-19T00:00:00+00:00");
+        assert_eq!(value["timestamp"], 1705622400);
+        assert_eq!(value_to_date(&value, &tokyo), Some(date));
+        assert_eq!(value_to_date(&Value::from(1705622400), &tokyo), Some(date));
+        assert!(parse_date("19/01/2024", &tokyo).is_none());
+        assert!(TimeZone::parse("Mars/Olympus").is_none());
+    }
+}
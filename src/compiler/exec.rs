@@ -0,0 +1,236 @@
+use crate::{
+    builder::{compile::panic_error, metadata::*},
+    *,
+};
+use std::io;
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing_error::SpanTrace;
+
+/// Data fed to the standard input of the command run by [`Exec`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExecInput {
+    /// The string or the binary data stored in [`BODY_META`]
+    #[default]
+    Body,
+    /// The content of the source file
+    Source,
+    /// Nothing
+    Null,
+}
+
+/// [`Exec`] runs an external command, and stores its standard output using [`BODY_META`] as
+/// the key. If the output is not valid UTF-8, it is stored as binary data.
+///
+/// The arguments are rendered as [`Tera`][tera::Tera] templates with metadata, so that the source
+/// path may be passed as `{{ _source }}`. The command runs in the current directory, and is killed
+/// when the compilation task is aborted, such as on timeout.
+/// The number of running commands is limited by [`Config::exec_concurrency`], separately from
+/// file system operations.
+/// If the command can not be run, [`Error::FileIo`] naming the program is returned. If the command
+/// exits with non-zero status, [`Error::Command`] including the standard error is returned.
+///
+/// ```
+/// use polysite::compiler::exec::{Exec, ExecInput};
+///
+/// let pandoc = Exec::new("pandoc")
+///     .args(["--from", "markdown", "--to", "html", "{{ _source }}"])
+///     .input(ExecInput::Null);
+/// ```
+#[derive(Clone)]
+pub struct Exec {
+    program: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    input: ExecInput,
+}
+
+impl Exec {
+    pub fn new(program: impl AsRef<str>) -> Self {
+        Self {
+            program: program.as_ref().to_owned(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            input: ExecInput::default(),
+        }
+    }
+    /// Add an argument, which is rendered with metadata
+    pub fn arg(mut self, arg: impl AsRef<str>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+    /// Add arguments, which are rendered with metadata
+    pub fn args(mut self, args: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_owned()));
+        self
+    }
+    /// Set an environment variable of the command
+    pub fn env(mut self, key: impl AsRef<str>, value: impl AsRef<str>) -> Self {
+        self.envs
+            .push((key.as_ref().to_owned(), value.as_ref().to_owned()));
+        self
+    }
+    /// Set the data fed to the standard input
+    pub fn input(mut self, input: ExecInput) -> Self {
+        self.input = input;
+        self
+    }
+
+    async fn render_args(&self, ctx: &Context) -> Result<Vec<String>, Error> {
+        let tera_ctx = tera::Context::from_serialize(ctx.metadata().read_lock().await)
+            .map_err(Error::user_error)?;
+        self.args
+            .iter()
+            .map(|arg| tera::Tera::one_off(arg, &tera_ctx, false).map_err(Error::user_error))
+            .collect()
+    }
+
+    async fn stdin(&self, ctx: &Context) -> Result<Option<Vec<u8>>, Error> {
+        match self.input {
+            ExecInput::Body => match ctx.body_bytes() {
+                Some(data) => Ok(Some(data.to_vec())),
+                None => Ok(Some(
+                    ctx.body()
+                        .await
+                        .ok_or_else(|| Error::missing_metadata(BODY_META))?
+                        .as_str()
+                        .ok_or_else(|| Error::metadata_type(BODY_META, "a string or bytes"))?
+                        .as_bytes()
+                        .to_vec(),
+                )),
+            },
+            ExecInput::Source => Ok(Some(ctx.source_body().await?)),
+            ExecInput::Null => Ok(None),
+        }
+    }
+}
+
+impl Compiler for Exec {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let exec = self.clone();
+        compile!({
+            let args = exec.render_args(&ctx).await?;
+            let input = exec.stdin(&ctx).await?;
+            let command = std::iter::once(exec.program.as_str())
+                .chain(args.iter().map(|a| a.as_str()))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let limit = ctx.exec_limit();
+            let _permit = limit.acquire().await.unwrap();
+            let mut child = Command::new(&exec.program)
+                .args(&args)
+                .envs(exec.envs.iter().map(|(k, v)| (k, v)))
+                .stdin(if input.is_some() {
+                    Stdio::piped()
+                } else {
+                    Stdio::null()
+                })
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|e| Error::FileIo {
+                    trace: SpanTrace::capture(),
+                    io_error: io::Error::new(
+                        e.kind(),
+                        format!("failed to run `{}`: {}", exec.program, e),
+                    ),
+                })?;
+            // write stdin concurrently, so that the command does not block on full stdout
+            let writer = match (child.stdin.take(), input) {
+                (Some(mut stdin), Some(input)) => Some(tokio::spawn(async move {
+                    stdin.write_all(&input).await?;
+                    stdin.shutdown().await
+                })),
+                _ => None,
+            };
+            let output = child
+                .wait_with_output()
+                .await
+                .map_err(|io_error| Error::FileIo {
+                    trace: SpanTrace::capture(),
+                    io_error,
+                })?;
+            if !output.status.success() {
+                return Err(Error::Command {
+                    trace: SpanTrace::capture(),
+                    command,
+                    status: output.status,
+                    stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+                });
+            }
+            if let Some(writer) = writer {
+                // the command may exit without reading all of stdin
+                match writer.await.map_err(panic_error)? {
+                    Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                        return Err(Error::FileIo {
+                            trace: SpanTrace::capture(),
+                            io_error: e,
+                        });
+                    }
+                    _ => (),
+                }
+            }
+            match String::from_utf8(output.stdout) {
+                Ok(s) => ctx
+                    .metadata_mut()
+                    .insert_local(BODY_META.to_owned(), Value::from(s)),
+                Err(e) => ctx
+                    .metadata_mut()
+                    .insert_local_bytes(BODY_META.to_owned(), e.into_bytes()),
+            }
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+// the tests run `tr` and `sh`
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::compiler::file::FileWriter;
+    use crate::fs::MemoryFileSystem;
+
+    #[tokio::test]
+    async fn exec_command() {
+        let fs = MemoryFileSystem::new()
+            .with_file("site/a.txt", "hello")
+            .with_file("site/b.txt", "");
+        let compiler = pipe!(
+            Exec::new("tr")
+                .args(["a-z", "A-Z"])
+                .input(ExecInput::Source),
+            Exec::new("sh").args(["-c", "cat; printf ' %s' \"$1\"", "sh", "{{ _source }}"]),
+            FileWriter::new(),
+        );
+        Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([Rule::new("exec", compiler).set_globs(["a.txt"])])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(fs.get_string("dist/a.txt").unwrap(), "HELLO site/a.txt");
+
+        let failing = Exec::new("sh")
+            .args(["-c", "echo oops >&2; exit 3"])
+            .input(ExecInput::Null);
+        let error = Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([Rule::new("exec", failing).set_globs(["b.txt"])])
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(error.cause(), Error::Command { stderr, .. } if stderr == "oops"));
+
+        let missing = Exec::new("polysite-missing-command").input(ExecInput::Null);
+        let error = Builder::new(Config::default().set_file_system(fs))
+            .add_step([Rule::new("exec", missing).set_globs(["b.txt"])])
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(error.cause(), Error::FileIo { io_error, .. }
+            if io_error.kind() == io::ErrorKind::NotFound
+                && io_error.to_string().contains("polysite-missing-command")));
+    }
+}
@@ -1,11 +1,16 @@
 use crate::{builder::metadata::*, *};
-use std::fs::copy;
-use std::io::Write;
-use tracing_error::SpanTrace;
 
-/// [`FileReader`] reads the source file as a [`String`] and stores the data using [`SOURCE_FILE_META`] as the key.
+/// [`FileReader`] reads the source file as a [`String`] and stores the data using [`BODY_META`] as the key.
+/// If the source file is not valid UTF-8, the data is stored as binary data by
+/// [`Metadata::insert_local_bytes`].
 #[derive(Clone)]
 pub struct FileReader;
+impl Default for FileReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileReader {
     pub fn new() -> Self {
         Self
@@ -16,11 +21,14 @@ impl Compiler for FileReader {
     fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
         compile!({
             let src = ctx.source_body().await?;
-            let meta = match String::from_utf8(src.clone()) {
-                Ok(s) => Value::from(s),
-                Err(_) => Value::from_bytes(&src),
-            };
-            ctx.metadata_mut().insert_local(BODY_META.to_owned(), meta);
+            match String::from_utf8(src) {
+                Ok(s) => ctx
+                    .metadata_mut()
+                    .insert_local(BODY_META.to_owned(), Value::from(s)),
+                Err(e) => ctx
+                    .metadata_mut()
+                    .insert_local_bytes(BODY_META.to_owned(), e.into_bytes()),
+            }
             Ok(CompileStep::Completed(ctx))
         })
     }
@@ -29,6 +37,12 @@ impl Compiler for FileReader {
 /// [`FileWriter`] writes the data stored in [`BODY_META`] to the target file, which path is saved in [`TARGET_FILE_META`].
 #[derive(Clone)]
 pub struct FileWriter;
+impl Default for FileWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FileWriter {
     pub fn new() -> Self {
         Self
@@ -38,23 +52,17 @@ impl Compiler for FileWriter {
     #[tracing::instrument(skip(self, ctx))]
     fn next_step(&mut self, ctx: Context) -> CompilerReturn {
         compile!({
-            let mut target = ctx.open_target().await?;
-            let body = ctx.body().await.ok_or(Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })?;
-            let write = if let Some(s) = body.as_str() {
-                target.write(s.as_bytes())
-            } else if let Some(bytes) = body.as_bytes() {
-                target.write(&bytes)
-            } else {
-                return Err(Error::InvalidMetadata {
-                    trace: SpanTrace::capture(),
-                });
+            let data = match ctx.body_bytes() {
+                Some(data) => data,
+                None => ctx
+                    .body()
+                    .await
+                    .ok_or_else(|| Error::missing_metadata(BODY_META))?
+                    .as_bytes()
+                    .ok_or_else(|| Error::metadata_type(BODY_META, "a string or bytes"))?
+                    .into(),
             };
-            write.map_err(|io_error| Error::FileIo {
-                trace: SpanTrace::capture(),
-                io_error,
-            })?;
+            ctx.write_target(data).await?;
             Ok(CompileStep::Completed(ctx))
         })
     }
@@ -63,6 +71,12 @@ impl Compiler for FileWriter {
 /// [`CopyCompiler`] simply copies source file to target file
 #[derive(Clone)]
 pub struct CopyCompiler;
+impl Default for CopyCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CopyCompiler {
     pub fn new() -> Self {
         Self
@@ -72,21 +86,8 @@ impl Compiler for CopyCompiler {
     #[tracing::instrument(skip(self, ctx))]
     fn next_step(&mut self, ctx: Context) -> CompilerReturn {
         compile!({
-            ctx.create_target_parent_dir().await?;
-            let src = ctx.source().await;
-            let tgt = ctx.target().await;
-            match (src, tgt) {
-                (Some(src), Some(tgt)) => {
-                    copy(src, tgt).map_err(|io_error| Error::FileIo {
-                        trace: SpanTrace::capture(),
-                        io_error,
-                    })?;
-                    Ok(CompileStep::Completed(ctx))
-                }
-                _ => Err(Error::InvalidMetadata {
-                    trace: SpanTrace::capture(),
-                }),
-            }
+            ctx.copy_source_to_target().await?;
+            Ok(CompileStep::Completed(ctx))
         })
     }
 }
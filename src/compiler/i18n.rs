@@ -0,0 +1,370 @@
+use crate::{builder::metadata::*, *};
+use serde::Serialize;
+use serde_json::{json, Map};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Metadata key of the translations of the same content, which is set by [`LinkTranslations`].
+pub const TRANSLATIONS_META: &str = "translations";
+/// Metadata key of the per-locale string table, which is set by [`I18n`].
+pub const STRINGS_META: &str = "i18n";
+/// Front matter key of the locale, which is read by [`I18n`].
+pub const LANG_FRONT_MATTER: &str = "lang";
+
+/// [`I18n`] detects the locale of the compiling file, and routes the file under the locale prefix.
+///
+/// The locale is detected from the first directory, such as `ja/post.md`, from
+/// [`LANG_FRONT_MATTER`] in front matter, or from the file name, such as `post.ja.md`, in this
+/// order. Files without locale are treated as the default locale.
+/// The locale is saved to [`LANG_META`], and the locale independent path, which is used to find
+/// translations, is saved to [`TRANSLATION_KEY_META`].
+/// The string table of the locale is saved to [`STRINGS_META`], falling back to the default
+/// locale's one.
+#[derive(Clone)]
+pub struct I18n {
+    default: String,
+    languages: Vec<String>,
+    prefix_default: bool,
+    strings: HashMap<String, Map<String, Value>>,
+}
+impl I18n {
+    pub fn new(
+        default: impl AsRef<str>,
+        languages: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Self {
+        let default = default.as_ref().to_owned();
+        let mut languages: Vec<_> = languages
+            .into_iter()
+            .map(|l| l.as_ref().to_owned())
+            .collect();
+        if !languages.contains(&default) {
+            languages.push(default.clone());
+        }
+        Self {
+            default,
+            languages,
+            prefix_default: false,
+            strings: HashMap::new(),
+        }
+    }
+    /// Route the default locale under its prefix too. The default is `false`.
+    pub fn prefix_default(mut self, prefix: bool) -> Self {
+        self.prefix_default = prefix;
+        self
+    }
+    /// Set the string table of the locale.
+    pub fn strings(
+        mut self,
+        lang: impl AsRef<str>,
+        strings: impl Serialize,
+    ) -> Result<Self, Error> {
+        let strings = match Metadata::to_value(strings)? {
+            Value::Object(map) => map,
+            _ => return Err(Error::metadata_type(STRINGS_META, "an object")),
+        };
+        self.strings.insert(lang.as_ref().to_owned(), strings);
+        Ok(self)
+    }
+
+    fn is_language(&self, lang: &str) -> bool {
+        self.languages.iter().any(|l| l == lang)
+    }
+
+    /// Detect the locale from the file name. Returns the locale and the file name without locale.
+    fn detect_file_name(&self, path: &Path) -> Option<(String, PathBuf)> {
+        let name = path.file_name()?.to_string_lossy();
+        let parts: Vec<_> = name.split('.').collect();
+        if parts.len() < 3 || !self.is_language(parts[parts.len() - 2]) {
+            return None;
+        }
+        let lang = parts[parts.len() - 2].to_owned();
+        let mut parts = parts;
+        parts.remove(parts.len() - 2);
+        Some((lang, path.with_file_name(parts.join("."))))
+    }
+
+    /// Detect the locale from the first directory. Returns the locale and the path without locale.
+    fn detect_dir(&self, path: &Path) -> Option<(String, PathBuf)> {
+        let mut components = path.components().skip_while(|c| c == &Component::RootDir);
+        let lang = components.next()?.as_os_str().to_string_lossy().to_string();
+        let rest: PathBuf = components.collect();
+        if self.is_language(&lang) && rest.components().next().is_some() {
+            Some((lang, PathBuf::from("/").join(rest)))
+        } else {
+            None
+        }
+    }
+
+    fn locale_strings(&self, lang: &str) -> Value {
+        let mut strings =
+            Value::Object(self.strings.get(&self.default).cloned().unwrap_or_default());
+        if let Some(s) = self.strings.get(lang) {
+            merge_values(&mut strings, Value::Object(s.clone()));
+        }
+        strings
+    }
+}
+impl Compiler for I18n {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let i18n = self.clone();
+        compile!({
+            let target = ctx
+                .target()
+                .await
+                .ok_or_else(|| Error::missing_metadata(TARGET_FILE_META))?;
+            let path = ctx
+                .path()
+                .await
+                .ok_or_else(|| Error::missing_metadata(PATH_META))?;
+            let target_dir = ctx.config().target_dir();
+            let front_matter = ctx
+                .metadata()
+                .local()
+                .get(LANG_FRONT_MATTER)
+                .and_then(|v| v.as_str())
+                .filter(|l| i18n.is_language(l))
+                .map(|l| l.to_owned());
+            let (lang, key, target, path) = if let Some((lang, key)) = i18n.detect_dir(&path) {
+                (lang, key, target, path)
+            } else {
+                let (lang, key) = i18n
+                    .detect_file_name(&path)
+                    .unwrap_or_else(|| (i18n.default.clone(), path.clone()));
+                let lang = front_matter.unwrap_or(lang);
+                let target = i18n
+                    .detect_file_name(&target)
+                    .map(|(_, t)| t)
+                    .unwrap_or(target);
+                if lang != i18n.default || i18n.prefix_default {
+                    let relative = target.strip_prefix(&target_dir).unwrap_or(&target);
+                    let target = target_dir.join(&lang).join(relative);
+                    let path = PathBuf::from("/")
+                        .join(&lang)
+                        .join(key.strip_prefix("/").unwrap_or(&key));
+                    (lang, key, target, path)
+                } else {
+                    (lang, key.clone(), target, key)
+                }
+            };
+            let strings = i18n.locale_strings(&lang);
+            let meta = ctx.metadata_mut();
+            meta.insert_local(LANG_META.to_owned(), Value::from(lang));
+            meta.insert_local(
+                TRANSLATION_KEY_META.to_owned(),
+                Value::from(key.to_string_lossy()),
+            );
+            meta.insert_local(
+                TARGET_FILE_META.to_owned(),
+                Value::from(target.to_string_lossy()),
+            );
+            meta.insert_local(PATH_META.to_owned(), Value::from(path.to_string_lossy()));
+            meta.insert_local(STRINGS_META.to_owned(), strings);
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+/// [`LinkTranslations`] finds the translations of the compiling file from all [`Version`]s, and
+/// saves them to [`TRANSLATIONS_META`] as an array of `{lang, _path}`.
+///
+/// Translations are found using [`TRANSLATION_KEY_META`] set by [`I18n`], so this must be used
+/// after the translations are processed by [`I18n`], e.g. after
+/// [`WaitStage`][crate::compiler::utils::WaitStage], after
+/// [`Barrier`][crate::compiler::utils::Barrier] shared by the rules of the locales, or in a later
+/// build step.
+#[derive(Clone)]
+pub struct LinkTranslations;
+impl Default for LinkTranslations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LinkTranslations {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl Compiler for LinkTranslations {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        compile!({
+            let local = ctx.metadata().local();
+            let key = local
+                .get(TRANSLATION_KEY_META)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| Error::missing_metadata(TRANSLATION_KEY_META))?
+                .to_owned();
+            let lang = local
+                .get(LANG_META)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| Error::missing_metadata(LANG_META))?
+                .to_owned();
+            let mut translations: Vec<(String, Value)> = Vec::new();
+            {
+                let global = ctx.metadata().global().await;
+                let versions = global
+                    .get(VERSIONS_META)
+                    .and_then(|v| v.as_object())
+                    .into_iter()
+                    .flat_map(|v| v.values())
+                    .filter_map(|v| v.as_object());
+                for files in versions {
+                    for meta in files.values() {
+                        let other_key = meta.get(TRANSLATION_KEY_META).and_then(|v| v.as_str());
+                        let other_lang = meta.get(LANG_META).and_then(|v| v.as_str());
+                        if let (Some(other_key), Some(other_lang)) = (other_key, other_lang) {
+                            if other_key == key
+                                && other_lang != lang
+                                && translations.iter().all(|(l, _)| l != other_lang)
+                            {
+                                translations.push((
+                                    other_lang.to_owned(),
+                                    json!({
+                                        "lang": other_lang,
+                                        PATH_META: meta.get(PATH_META),
+                                    }),
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+            translations.sort_by(|(a, _), (b, _)| a.cmp(b));
+            ctx.metadata_mut().insert_local(
+                TRANSLATIONS_META.to_owned(),
+                Value::Array(translations.into_iter().map(|(_, v)| v).collect()),
+            );
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{file::FileReader, markdown::FrontMatterParser, utils::Barrier};
+    use crate::fs::MemoryFileSystem;
+
+    /// Write the locale metadata to the target as JSON
+    fn summary(ctx: Context) -> CompilerReturn {
+        compile!({
+            let local = ctx.metadata().local();
+            let summary = json!({
+                "lang": local[LANG_META],
+                "key": local[TRANSLATION_KEY_META],
+                "path": local[PATH_META],
+                "translations": local.get(TRANSLATIONS_META),
+                "hello": local[STRINGS_META]["hello"],
+            });
+            ctx.write_target(summary.to_string()).await?;
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+
+    fn i18n() -> I18n {
+        I18n::new("en", ["ja"])
+            .strings("en", json!({"hello": "hello"}))
+            .unwrap()
+    }
+
+    fn site() -> MemoryFileSystem {
+        MemoryFileSystem::new()
+            .with_file("site/post.md", "")
+            .with_file("site/post.ja.md", "")
+            .with_file("site/ja/about.md", "")
+            .with_file("site/about.md", "")
+            .with_file("site/note.md", "---\nlang: ja\n---\n")
+    }
+
+    fn read(fs: &MemoryFileSystem, path: &str) -> Value {
+        serde_json::from_str(&fs.get_string(path).unwrap()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn detect_locale() {
+        let fs = site();
+        let compiler = pipe!(FileReader::new(), FrontMatterParser::new(), i18n(), summary);
+        Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([Rule::new("i18n", compiler).set_globs(["**/*.md"])])
+            .build()
+            .await
+            .unwrap();
+        let post = read(&fs, "dist/post.md");
+        assert_eq!(
+            (&post["lang"], &post["path"]),
+            (&json!("en"), &json!("/post.md"))
+        );
+        let post = read(&fs, "dist/ja/post.md");
+        assert_eq!(post["lang"], "ja");
+        assert_eq!(
+            (&post["key"], &post["path"]),
+            (&json!("/post.md"), &json!("/ja/post.md"))
+        );
+        // strings fall back to the default locale
+        assert_eq!(post["hello"], "hello");
+        let about = read(&fs, "dist/ja/about.md");
+        assert_eq!(
+            (&about["lang"], &about["key"]),
+            (&json!("ja"), &json!("/about.md"))
+        );
+        let note = read(&fs, "dist/ja/note.md");
+        assert_eq!(
+            (&note["lang"], &note["path"]),
+            (&json!("ja"), &json!("/ja/note.md"))
+        );
+
+        // the default locale is routed under its prefix
+        let fs = site();
+        Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([
+                Rule::new("i18n", pipe!(i18n().prefix_default(true), summary)).set_globs(["post*"]),
+            ])
+            .build()
+            .await
+            .unwrap();
+        let post = read(&fs, "dist/en/post.md");
+        assert_eq!(
+            (&post["key"], &post["path"]),
+            (&json!("/post.md"), &json!("/en/post.md"))
+        );
+        assert!(fs.get("dist/ja/post.md").is_some());
+    }
+
+    #[tokio::test]
+    async fn link_translations() {
+        let fs = site();
+        let compiler = |i18n: I18n| {
+            pipe!(
+                FileReader::new(),
+                FrontMatterParser::new(),
+                i18n,
+                Barrier::new("i18n"),
+                LinkTranslations::new(),
+                summary,
+            )
+        };
+        // each locale is compiled as its own version
+        Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([
+                Rule::new("ja", compiler(i18n()))
+                    .set_globs(["*.ja.md", "ja/*"])
+                    .set_version("ja"),
+                Rule::new("en", compiler(i18n())).set_globs(["post.md", "about.md"]),
+            ])
+            .build()
+            .await
+            .unwrap();
+        let post = read(&fs, "dist/post.md");
+        assert_eq!(
+            post["translations"],
+            json!([{"lang": "ja", "_path": "/ja/post.md"}])
+        );
+        let about = read(&fs, "dist/ja/about.md");
+        assert_eq!(
+            about["translations"],
+            json!([{"lang": "en", "_path": "/about.md"}])
+        );
+    }
+}
@@ -6,12 +6,86 @@ use crate::{
         template::{TemplateEngine, TemplateRenderer},
         utils::{PipeCompiler, WaitStage},
     },
+    error::CodeFrame,
     *,
 };
 use pulldown_cmark::{html::push_html, Options, Parser};
-use tracing_error::SpanTrace;
+use std::path::PathBuf;
+
+/// Split front matter from the body. Returns [`None`] as the front matter if the body has no front matter.
+pub(crate) fn parse_front_matter(
+    body: &str,
+    source: Option<PathBuf>,
+) -> Result<(Option<Value>, &str), Error> {
+    match fronma::parser::parse::<Value>(body) {
+        Ok(fm) => Ok((Some(fm.headers), fm.body)),
+        Err(fronma::error::Error::MissingBeginningLine) => Ok((None, body)),
+        Err(fronma::error::Error::MissingEndingLine) => Err(Error::syntax(
+            "front matter is not closed",
+            Some(CodeFrame::new(source, body, 0)),
+        )),
+        Err(fronma::error::Error::SerdeYaml(e)) => {
+            let begin = if body.starts_with("---\r\n") { 5 } else { 4 };
+            let frame = e
+                .location()
+                .map(|l| CodeFrame::new(source, body, begin + l.index()));
+            Err(Error::syntax(format!("invalid front matter: {}", e), frame))
+        }
+    }
+}
+
+fn insert_front_matter(ctx: &mut Context, front_matter: Option<Value>) {
+    if let Some(Value::Object(map)) = front_matter {
+        for (k, v) in map.into_iter() {
+            ctx.metadata_mut().insert_local(k, v);
+        }
+    }
+}
+
+/// [`FrontMatterParser`] reads the front matter from the body in [`BODY_META`], saves each key as local metadata, and saves the rest of the body to [`BODY_META`].
+/// The line at which the rest of the body begins is saved to [`BODY_LINE_META`].
+/// This may be used to make front matter available to compilers running before [`MarkdownRenderer`].
+#[derive(Clone)]
+pub struct FrontMatterParser;
+impl Default for FrontMatterParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrontMatterParser {
+    pub fn new() -> Self {
+        Self
+    }
+}
+impl Compiler for FrontMatterParser {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        compile!({
+            let body = ctx
+                .body()
+                .await
+                .ok_or_else(|| Error::missing_metadata(BODY_META))?;
+            let body = body
+                .as_str()
+                .ok_or_else(|| Error::metadata_type(BODY_META, "a string"))?;
+            let (front_matter, rest) = parse_front_matter(body, ctx.source().await)?;
+            if front_matter.is_some() {
+                let line = body[..body.len() - rest.len()].matches('\n').count() + 1;
+                ctx.metadata_mut()
+                    .insert_local(BODY_LINE_META.to_owned(), Value::from(line));
+            }
+            let body = rest.to_owned();
+            insert_front_matter(&mut ctx, front_matter);
+            ctx.metadata_mut()
+                .insert_local(BODY_META.to_owned(), Value::String(body));
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
 
 /// [`MarkdownRenderer`] reads the body from [`BODY_META`], renders it to HTML, and saves the HTML to [`BODY_META`].
+/// If the body starts with front matter, it is saved as local metadata.
 #[derive(Clone)]
 pub struct MarkdownRenderer {
     options: Options,
@@ -25,26 +99,20 @@ impl MarkdownRenderer {
 impl Compiler for MarkdownRenderer {
     #[tracing::instrument(skip(self, ctx))]
     fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
-        let options = self.options.clone();
+        let options = self.options;
         compile!({
-            let body = ctx.body().await.ok_or(Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })?;
-            let body = body.as_str().ok_or(Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })?;
-            let fm = fronma::parser::parse::<Value>(&body).map_err(|_| Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })?;
-            let file_metadata = fm.headers;
-            let parser = Parser::new_ext(fm.body, options);
+            let body = ctx
+                .body()
+                .await
+                .ok_or_else(|| Error::missing_metadata(BODY_META))?;
+            let body = body
+                .as_str()
+                .ok_or_else(|| Error::metadata_type(BODY_META, "a string"))?;
+            let (front_matter, body) = parse_front_matter(body, ctx.source().await)?;
+            let parser = Parser::new_ext(body, options);
             let mut html = String::new();
             push_html(&mut html, parser);
-            if let Value::Object(map) = file_metadata {
-                for (k, v) in map.into_iter() {
-                    ctx.metadata_mut().insert_local(k, v);
-                }
-            }
+            insert_front_matter(&mut ctx, front_matter);
             ctx.metadata_mut()
                 .insert_local(BODY_META.to_owned(), Value::String(html));
             Ok(CompileStep::Completed(ctx))
@@ -81,4 +149,15 @@ impl Compiler for MarkdownCompiler {
     fn next_step(&mut self, ctx: Context) -> CompilerReturn {
         self.compiler.next_step(ctx)
     }
+    fn barriers(&self) -> Vec<String> {
+        self.compiler.barriers()
+    }
+    fn stage(&self) -> Vec<usize> {
+        self.compiler.stage()
+    }
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(Self {
+            compiler: self.compiler.restart(),
+        })
+    }
 }
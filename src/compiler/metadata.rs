@@ -8,6 +8,12 @@ pub struct SetMetadata {
     compiling: HashMap<String, Value>,
     global: HashMap<String, Value>,
 }
+impl Default for SetMetadata {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SetMetadata {
     pub fn new() -> Self {
         Self {
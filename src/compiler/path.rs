@@ -2,7 +2,6 @@ use crate::{
     builder::metadata::{PATH_META, TARGET_FILE_META},
     *,
 };
-use tracing_error::SpanTrace;
 
 /// [`SetExtension`] changes target file's extension and URL path extension to specified one.
 #[derive(Clone)]
@@ -17,12 +16,14 @@ impl Compiler for SetExtension {
     fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
         let ext = self.0.clone();
         compile!({
-            let mut target = ctx.target().await.ok_or(Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })?;
-            let mut path = ctx.path().await.ok_or(Error::InvalidMetadata {
-                trace: SpanTrace::capture(),
-            })?;
+            let mut target = ctx
+                .target()
+                .await
+                .ok_or_else(|| Error::missing_metadata(TARGET_FILE_META))?;
+            let mut path = ctx
+                .path()
+                .await
+                .ok_or_else(|| Error::missing_metadata(PATH_META))?;
             target.set_extension(ext.clone());
             ctx.metadata_mut().insert_local(
                 TARGET_FILE_META.to_owned(),
@@ -0,0 +1,450 @@
+use crate::{
+    builder::{compile::panic_error, metadata::*},
+    error::CodeFrame,
+    fs::FileSystem,
+    *,
+};
+use rhai::{
+    module_resolvers::DummyModuleResolver, Dynamic, Engine, EvalAltResult, Position, Scope, AST,
+};
+use serde_json::Map;
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::time::SystemTime;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+/// Compiled script with its text, and the modified time of the file
+type CachedScript = Option<(SystemTime, Arc<(AST, String)>)>;
+
+/// The default limit of the operations of one call of the script
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+thread_local! {
+    /// Cancellation flag of the script running on this thread
+    static CANCELLED: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+/// Set the cancellation flag when the step is dropped, such as on timeout
+struct CancelOnDrop(Arc<AtomicBool>);
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// State of one call of the script, shared by the clones of [`ScriptContext`]
+struct ScriptState {
+    metadata: Map<String, Value>,
+    local: Map<String, Value>,
+    global: Map<String, Value>,
+    source: Option<PathBuf>,
+    fs: Arc<dyn FileSystem>,
+}
+
+/// `ctx` passed to the `next_step` function of scripts
+#[derive(Clone)]
+struct ScriptContext(Arc<Mutex<ScriptState>>);
+
+impl ScriptContext {
+    fn get(&mut self, key: &str) -> ScriptResult<Dynamic> {
+        let state = self.0.lock().unwrap();
+        match state.local.get(key).or_else(|| state.metadata.get(key)) {
+            Some(value) => rhai::serde::to_dynamic(value),
+            None => Ok(Dynamic::UNIT),
+        }
+    }
+    fn metadata(&mut self) -> ScriptResult<Dynamic> {
+        let state = self.0.lock().unwrap();
+        let mut metadata = state.metadata.clone();
+        metadata.extend(state.local.clone());
+        rhai::serde::to_dynamic(metadata)
+    }
+    fn set_local(&mut self, key: &str, value: Dynamic) -> ScriptResult<()> {
+        let value = rhai::serde::from_dynamic(&value)?;
+        self.0.lock().unwrap().local.insert(key.to_owned(), value);
+        Ok(())
+    }
+    fn set_global(&mut self, key: &str, value: Dynamic) -> ScriptResult<()> {
+        let value = rhai::serde::from_dynamic(&value)?;
+        self.0.lock().unwrap().global.insert(key.to_owned(), value);
+        Ok(())
+    }
+    fn body(&mut self) -> ScriptResult<Dynamic> {
+        self.get(BODY_META)
+    }
+    fn set_body(&mut self, body: &str) {
+        self.0
+            .lock()
+            .unwrap()
+            .local
+            .insert(BODY_META.to_owned(), Value::from(body));
+    }
+    fn source(&mut self) -> Dynamic {
+        match &self.0.lock().unwrap().source {
+            Some(source) => source.to_string_lossy().into_owned().into(),
+            None => Dynamic::UNIT,
+        }
+    }
+    fn source_string(&mut self) -> ScriptResult<String> {
+        let state = self.0.lock().unwrap();
+        let source = state
+            .source
+            .as_ref()
+            .ok_or_else(|| format!("missing metadata `{}`", SOURCE_FILE_META))?;
+        let data = state
+            .fs
+            .read(source)
+            .map_err(|e| format!("failed to read {}: {}", source.display(), e))?;
+        Ok(String::from_utf8(data).map_err(|_| "source is not valid UTF-8")?)
+    }
+}
+
+fn engine(max_operations: u64) -> Engine {
+    let mut engine = Engine::new();
+    // scripts can not import modules from files
+    engine.set_module_resolver(DummyModuleResolver::new());
+    // scripts can not hang or exhaust the blocking thread
+    engine
+        .set_max_operations(max_operations)
+        .set_max_call_levels(64)
+        .set_max_expr_depths(64, 64)
+        .set_max_string_size(64 * 1024 * 1024)
+        .set_max_array_size(1_000_000)
+        .set_max_map_size(1_000_000)
+        .on_progress(|_| {
+            let cancelled =
+                CANCELLED.with_borrow(|c| c.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)));
+            cancelled.then_some(Dynamic::UNIT)
+        });
+    engine
+        .register_type_with_name::<ScriptContext>("Context")
+        .register_fn("get", ScriptContext::get)
+        .register_fn("metadata", ScriptContext::metadata)
+        .register_fn("set_local", ScriptContext::set_local)
+        .register_fn("set_global", ScriptContext::set_global)
+        .register_fn("body", ScriptContext::body)
+        .register_fn("set_body", ScriptContext::set_body)
+        .register_fn("source", ScriptContext::source)
+        .register_fn("source_string", ScriptContext::source_string);
+    engine
+}
+
+/// Create the code frame of the position in the script
+fn code_frame(path: &Path, text: &str, position: Position) -> Option<CodeFrame> {
+    position.line().map(|line| CodeFrame {
+        file: Some(path.to_owned()),
+        line,
+        column: position.position().unwrap_or(1),
+        snippet: text.lines().nth(line - 1).unwrap_or("").to_owned(),
+    })
+}
+
+/// Convert the error of the script into [`Error::Script`], which has the position of the
+/// innermost error
+fn script_error(path: &Path, text: &str, error: EvalAltResult) -> Error {
+    match error {
+        EvalAltResult::ErrorInFunctionCall(.., error, _)
+        | EvalAltResult::ErrorInModule(.., error, _) => script_error(path, text, *error),
+        mut error => {
+            let position = error.take_position();
+            Error::script(error.to_string(), code_frame(path, text, position))
+        }
+    }
+}
+
+/// [`Script`] is the compiler written in [Rhai](https://rhai.rs) script.
+/// This compiler is available with `script` feature.
+///
+/// The script file is read through [`FileSystem`], and its `next_step(ctx)` function is called on
+/// each step. The script is compiled again when the file is modified, so the changes are used by
+/// the next build in watch mode.
+/// The following methods are available for `ctx`:
+/// - `get(key)`: get metadata, which is `()` if missing
+/// - `metadata()`: get all metadata as a map, which is the same as passed to templates
+/// - `set_local(key, value)`, `set_global(key, value)`: insert local or global metadata
+/// - `body()`, `set_body(body)`: get or set [`BODY_META`]
+/// - `source()`, `source_string()`: get the source file path or the content
+///
+/// The function may return `"in_progress"` or `"wait_stage"` to continue the task as
+/// [`CompileStep::InProgress`] or [`CompileStep::WaitStage`]. Otherwise the task is completed.
+///
+/// Scripts are sandboxed: they can not access files except by `ctx`, nor import modules.
+/// The operations, the call depth, and the sizes of strings, arrays and maps are limited, and
+/// the script is terminated when the step is aborted, such as on timeout.
+/// Runtime errors are returned as [`Error::Script`] with the location in the script.
+///
+/// ```text
+/// fn next_step(ctx) {
+///     let title = ctx.get("title");
+///     ctx.set_body(`<h1>${title}</h1>` + ctx.body());
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Script {
+    path: PathBuf,
+    engine: Arc<Engine>,
+    /// Shared by all tasks
+    cache: Arc<Mutex<CachedScript>>,
+}
+
+impl Script {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            engine: Arc::new(engine(MAX_OPERATIONS)),
+            cache: Arc::new(Mutex::new(None)),
+        }
+    }
+    /// Limit the number of operations of one call of the script. The default is 10,000,000.
+    pub fn max_operations(mut self, operations: u64) -> Self {
+        self.engine = Arc::new(engine(operations));
+        self
+    }
+
+    /// Load the script, or get the cached one if the file is not modified
+    async fn load(&self, ctx: &Context) -> Result<Arc<(AST, String)>, Error> {
+        let path = self.path.clone();
+        let modified = ctx.with_file_system(move |fs| fs.modified(&path)).await?;
+        if let Some((time, script)) = &*self.cache.lock().unwrap() {
+            if *time == modified {
+                return Ok(script.clone());
+            }
+        }
+        let path = self.path.clone();
+        let data = ctx.with_file_system(move |fs| fs.read(&path)).await?;
+        let text = String::from_utf8_lossy(&data).into_owned();
+        let ast = self.engine.compile(&text).map_err(|e| {
+            let frame = code_frame(&self.path, &text, e.position());
+            Error::syntax(format!("invalid script: {}", e.err_type()), frame)
+        })?;
+        let script = Arc::new((ast, text));
+        *self.cache.lock().unwrap() = Some((modified, script.clone()));
+        Ok(script)
+    }
+}
+
+impl Compiler for Script {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let script = self.clone();
+        compile!({
+            let loaded = script.load(&ctx).await?;
+            let metadata = match Metadata::to_value(ctx.metadata().read_lock().await)? {
+                Value::Object(map) => map,
+                _ => Map::new(),
+            };
+            let state = Arc::new(Mutex::new(ScriptState {
+                metadata,
+                local: Map::new(),
+                global: Map::new(),
+                source: ctx.source().await,
+                fs: ctx.file_system(),
+            }));
+            let script_ctx = ScriptContext(state.clone());
+            let engine = script.engine.clone();
+            let cancelled = Arc::new(AtomicBool::new(false));
+            let _cancel = CancelOnDrop(cancelled.clone());
+            let call = loaded.clone();
+            let res = tokio::task::spawn_blocking(move || {
+                CANCELLED.set(Some(cancelled));
+                let res = engine.call_fn::<Dynamic>(
+                    &mut Scope::new(),
+                    &call.0,
+                    "next_step",
+                    (script_ctx,),
+                );
+                CANCELLED.set(None);
+                res
+            })
+            .await
+            .map_err(panic_error)?
+            .map_err(|e| script_error(&script.path, &loaded.1, *e))?;
+            let (local, global) = {
+                let mut state = state.lock().unwrap();
+                (
+                    std::mem::take(&mut state.local),
+                    std::mem::take(&mut state.global),
+                )
+            };
+            for (k, v) in local {
+                ctx.metadata_mut().insert_local(k, v);
+            }
+            for (k, v) in global {
+                ctx.metadata().insert_global(k, v).await;
+            }
+            match res.into_immutable_string().as_deref() {
+                Ok("in_progress") => Ok(CompileStep::InProgress(ctx)),
+                Ok("wait_stage") => Ok(CompileStep::WaitStage(ctx)),
+                _ => Ok(CompileStep::Completed(ctx)),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::file::{FileReader, FileWriter};
+    use crate::fs::MemoryFileSystem;
+
+    const SCRIPT: &str = r#"
+fn next_step(ctx) {
+    if ctx.get("step") == () {
+        ctx.set_local("step", 1);
+        return "in_progress";
+    }
+    ctx.set_body(ctx.source() + ": " + ctx.body() + ctx.source_string().len());
+    ctx.set_global("site", #{ title: ctx.get("site_title") });
+}
+"#;
+
+    #[tokio::test]
+    async fn script_compiler() {
+        let fs = MemoryFileSystem::new()
+            .with_file("next_step.rhai", SCRIPT)
+            .with_file(
+                "sandbox.rhai",
+                r#"import "next_step" as s; fn next_step(ctx) {}"#,
+            )
+            .with_file("site/a", "hello");
+        let check = |ctx: Context| {
+            compile!({
+                assert_eq!(ctx.body().await.unwrap(), "site/a: hello5");
+                assert_eq!(ctx.metadata().global().await["site"]["title"], "polysite");
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let config = Config::default()
+            .set_file_system(fs.clone())
+            .set_title("polysite");
+        let compiler = pipe!(FileReader::new(), Script::new("next_step.rhai"), check);
+        Builder::new(config.clone())
+            .add_step([Rule::new("script", compiler).set_globs(["a"])])
+            .build()
+            .await
+            .unwrap();
+
+        let error = Builder::new(config.clone())
+            .add_step([Rule::new("script", Script::new("sandbox.rhai")).set_globs(["a"])])
+            .build()
+            .await
+            .unwrap_err();
+        // the import is rejected at the position of the statement
+        match error.cause() {
+            Error::Script { message, frame, .. } => {
+                assert!(
+                    message.contains("Module not found: next_step"),
+                    "{}",
+                    message
+                );
+                let frame = frame.as_ref().unwrap();
+                assert_eq!((frame.line, frame.column), (1, 8));
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+
+        // the modified script is reloaded
+        let script = Script::new("reload.rhai");
+        for body in ["one", "two"] {
+            fs.insert(
+                "reload.rhai",
+                format!("fn next_step(ctx) {{ ctx.set_body(\"{}\") }}", body),
+            );
+            Builder::new(config.clone())
+                .add_step([
+                    Rule::new("script", pipe!(script.clone(), FileWriter::new())).set_globs(["a"]),
+                ])
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(fs.get_string("dist/a").unwrap(), body);
+        }
+    }
+
+    #[tokio::test]
+    async fn script_wait_stage() {
+        let script = r#"
+fn next_step(ctx) {
+    if ctx.get("waited") == () {
+        ctx.set_global(ctx.source(), true);
+        ctx.set_local("waited", true);
+        return "wait_stage";
+    }
+    let global = ctx.metadata();
+    ctx.set_body(`${global["site/a"]} ${global["site/b"]}`);
+}
+"#;
+        let fs = MemoryFileSystem::new()
+            .with_file("wait.rhai", script)
+            .with_file("site/a", "")
+            .with_file("site/b", "");
+        let config = Config::default().set_file_system(fs.clone());
+        // each task sees the global metadata set by the other before the stage
+        Builder::new(config)
+            .add_step([
+                Rule::new("script", pipe!(Script::new("wait.rhai"), FileWriter::new()))
+                    .set_globs(["*"]),
+            ])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(fs.get_string("dist/a").unwrap(), "true true");
+        assert_eq!(fs.get_string("dist/b").unwrap(), "true true");
+    }
+
+    #[tokio::test]
+    async fn script_errors() {
+        let fs = MemoryFileSystem::new()
+            .with_file(
+                "error.rhai",
+                "fn next_step(ctx) {\n    let x = 1;\n    ctx.set_body(x + missing);\n}\n",
+            )
+            .with_file("loop.rhai", "fn next_step(ctx) { loop {} }")
+            .with_file("site/a", "");
+        let config = Config::default().set_file_system(fs);
+        let error = Builder::new(config.clone())
+            .add_step([Rule::new("script", Script::new("error.rhai")).set_globs(["a"])])
+            .build()
+            .await
+            .unwrap_err();
+        match error.cause() {
+            Error::Script { message, frame, .. } => {
+                assert_eq!(message, "Variable not found: missing");
+                let frame = frame.as_ref().unwrap();
+                assert_eq!((frame.line, frame.column), (3, 22));
+                assert_eq!(frame.snippet, "    ctx.set_body(x + missing);");
+            }
+            error => panic!("unexpected error: {}", error),
+        }
+        let report = error.report().color(false).to_string();
+        assert!(report.contains("--> error.rhai:3:22"), "{}", report);
+
+        // the endless loop is stopped by the operation limit
+        let error = Builder::new(config.clone())
+            .add_step([
+                Rule::new("script", Script::new("loop.rhai").max_operations(1000)).set_globs(["a"]),
+            ])
+            .build()
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Too many operations"),
+            "{}",
+            error
+        );
+
+        // the script is terminated when the step times out
+        let error = Builder::new(config)
+            .add_step([Rule::new("script", Script::new("loop.rhai"))
+                .set_globs(["a"])
+                .set_timeout(std::time::Duration::from_millis(50))])
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(error.cause(), Error::Timeout { .. }));
+    }
+}
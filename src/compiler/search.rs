@@ -0,0 +1,403 @@
+use crate::{builder::metadata::*, *};
+use serde_json::{json, Map};
+use tracing_error::SpanTrace;
+
+/// A field of [`SearchIndex`] documents.
+#[derive(Clone, Debug)]
+pub struct SearchField {
+    key: String,
+    name: String,
+    boost: f64,
+    limit: Option<usize>,
+}
+impl SearchField {
+    /// Create new field, which reads the metadata of the specified key.
+    pub fn new(key: impl AsRef<str>) -> Self {
+        let key = key.as_ref().to_owned();
+        Self {
+            name: key.trim_start_matches('_').to_owned(),
+            key,
+            boost: 1.0,
+            limit: None,
+        }
+    }
+    /// Set the field name in the index. The default is the metadata key without leading `_`.
+    pub fn name(mut self, name: impl AsRef<str>) -> Self {
+        self.name = name.as_ref().to_owned();
+        self
+    }
+    /// Set the field boost, which is written to the index for the search library.
+    pub fn boost(mut self, boost: f64) -> Self {
+        self.boost = boost;
+        self
+    }
+    /// Limit the field length to the specified number of characters.
+    pub fn limit(mut self, chars: usize) -> Self {
+        self.limit = Some(chars);
+        self
+    }
+}
+
+/// [`SearchIndex`] collects documents from the results of the specified rules, and saves a compact
+/// JSON search index to [`BODY_META`].
+///
+/// HTML tags are stripped from each field. Pages with `search: false` are excluded.
+/// The index has `fields`, which lists field names and boosts, and `docs`, which lists documents
+/// with `id`, `path` and each field, so it can be loaded into lunr, elasticlunr, or a small search
+/// script.
+///
+/// This must be used in a build step after the specified rules, and the fields must be published
+/// to global metadata by the rules. [`BODY_META`] is not published by default, so the rules must
+/// publish it by [`Rule::set_publish`], or documents have no body field.
+///
+/// # Example
+/// ```
+/// use polysite::{
+///     compiler::{file::*, markdown::MarkdownRenderer, search::*},
+///     *,
+/// };
+/// Builder::new(Config::default())
+///     .add_step([Rule::new(
+///         "posts",
+///         pipe!(FileReader::new(), MarkdownRenderer::new(None)),
+///     )
+///     .set_globs(["posts/**/*.md"])
+///     .set_publish(Publish::only(["title", "_body"]))])
+///     .add_step([Rule::new(
+///         "search",
+///         pipe!(
+///             SearchIndex::new(["posts"])
+///                 .field(SearchField::new("title").boost(10.0))
+///                 .field(SearchField::new("_body").limit(1000)),
+///             FileWriter::new(),
+///         ),
+///     )
+///     .set_create(["search.json"])]);
+/// ```
+#[derive(Clone)]
+pub struct SearchIndex {
+    rules: Vec<String>,
+    fields: Vec<SearchField>,
+}
+impl SearchIndex {
+    pub fn new(rules: impl IntoIterator<Item = impl AsRef<str>>) -> Self {
+        Self {
+            rules: rules.into_iter().map(|r| r.as_ref().to_owned()).collect(),
+            fields: Vec::new(),
+        }
+    }
+    /// Add a field. If no field is added, `title` and `_body` are indexed.
+    pub fn field(mut self, field: SearchField) -> Self {
+        self.fields.push(field);
+        self
+    }
+
+    fn fields(&self) -> Vec<SearchField> {
+        if self.fields.is_empty() {
+            vec![
+                SearchField::new("title").boost(10.0),
+                SearchField::new("_body"),
+            ]
+        } else {
+            self.fields.clone()
+        }
+    }
+
+    /// Build the search index from rule results
+    pub fn build(&self, results: &Map<String, Value>) -> Value {
+        let fields = self.fields();
+        let mut docs = Vec::new();
+        for rule in &self.rules {
+            let pages = results
+                .get(rule)
+                .and_then(|v| v.as_array())
+                .into_iter()
+                .flatten();
+            for page in pages {
+                if page.get("search").and_then(|v| v.as_bool()) == Some(false) {
+                    continue;
+                }
+                let mut doc = Map::new();
+                doc.insert("id".to_owned(), Value::from(docs.len()));
+                doc.insert(
+                    "path".to_owned(),
+                    page.get(PATH_META).cloned().unwrap_or(Value::Null),
+                );
+                for field in &fields {
+                    if let Some(text) = page.get(&field.key).map(plain_text) {
+                        let text = match field.limit {
+                            Some(limit) => truncate(&text, limit),
+                            None => text,
+                        };
+                        doc.insert(field.name.clone(), Value::String(text));
+                    }
+                }
+                docs.push(Value::Object(doc));
+            }
+        }
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|f| json!({ "name": f.name, "boost": f.boost }))
+            .collect();
+        json!({
+            "fields": fields,
+            "docs": docs,
+        })
+    }
+}
+impl Compiler for SearchIndex {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let index = self.clone();
+        compile!({
+            let index = {
+                let global = ctx.metadata().global().await;
+                index.build(&global)
+            };
+            let body = serde_json::to_string(&index).map_err(|serde_error| Error::SerdeJson {
+                trace: SpanTrace::capture(),
+                serde_error,
+            })?;
+            ctx.metadata_mut()
+                .insert_local(BODY_META.to_owned(), Value::String(body));
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+fn plain_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => strip_html(s),
+        Value::Array(array) => array.iter().map(plain_text).collect::<Vec<_>>().join(" "),
+        Value::Null => String::new(),
+        v => v.to_string(),
+    }
+}
+
+/// HTML tags which separate words, such as block-level elements and `<br>`
+const BREAKING_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "br",
+    "dd",
+    "details",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "summary",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Strip HTML tags, decode basic entities, and collapse whitespaces.
+/// Block-level tags and `<br>` are replaced with a space, and inline tags are removed, so that
+/// `wo<b>rd</b>` is one word.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find('>').map(|i| i + 1).unwrap_or(rest.len());
+        let tag = rest[1..end].trim_start().to_ascii_lowercase();
+        rest = &rest[end..];
+        let name = tag
+            .trim_start_matches('/')
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default();
+        if BREAKING_TAGS.contains(&name) {
+            text.push(' ');
+        }
+        for skip in ["script", "style"] {
+            if tag.starts_with(skip) {
+                let close = format!("</{}", skip);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(i) => &rest[i..],
+                    None => "",
+                };
+            }
+        }
+    }
+    text.push_str(rest);
+    let text = decode_entities(&text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "nbsp" => Some(' '),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .or_else(|| entity.strip_prefix('#').and_then(|d| d.parse().ok()))
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                res.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+    res
+}
+
+fn truncate(text: &str, limit: usize) -> String {
+    match text.char_indices().nth(limit) {
+        // the cut is at the end of a word
+        Some((i, ' ')) => text[..i].to_owned(),
+        Some((i, _)) => {
+            let cut = &text[..i];
+            match cut.rfind(' ') {
+                Some(space) if space > 0 => cut[..space].to_owned(),
+                _ => cut.to_owned(),
+            }
+        }
+        None => text.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_and_decode() {
+        assert_eq!(
+            strip_html(
+                "<p>Hello,<br/>world</p><script>var a = '<p>';</script><STYLE>p {}</STYLE>!"
+            ),
+            "Hello, world !"
+        );
+        assert_eq!(
+            strip_html("<p>em<em>pha</em>sis</p><p>next<br>line</p><li>a</li><li>b</li>"),
+            "emphasis next line a b"
+        );
+        assert_eq!(
+            decode_entities("&lt;a&gt; &amp;&amp; &#65;&#x42;&#X43; &nbsp;&unknown; & x"),
+            "<a> && ABC  &unknown; & x"
+        );
+        assert_eq!(strip_html("a &lt;b&gt;"), "a <b>");
+    }
+
+    #[test]
+    fn truncate_at_char_boundary() {
+        assert_eq!(truncate("hello world", 8), "hello");
+        assert_eq!(truncate("日本語のテキスト", 3), "日本語");
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("ab cdé", 5), "ab");
+    }
+
+    #[test]
+    fn build_index() {
+        let results = json!({
+            "posts": [
+                {"_path": "/a.html", "title": "<b>A</b>", "_body": "<p>long body text</p>", "tags": ["x", "y"]},
+                {"_path": "/b.html", "title": "B", "search": false},
+                {"_path": "/c.html", "title": "C"},
+            ],
+            "pages": [{"_path": "/d.html", "title": "D"}],
+        });
+        let index = SearchIndex::new(["posts", "pages"])
+            .field(SearchField::new("title").boost(10.0))
+            .field(SearchField::new("_body").limit(9))
+            .field(SearchField::new("tags").name("keywords"))
+            .build(results.as_object().unwrap());
+        assert_eq!(
+            index["fields"],
+            json!([
+                {"name": "title", "boost": 10.0},
+                {"name": "body", "boost": 1.0},
+                {"name": "keywords", "boost": 1.0},
+            ])
+        );
+        assert_eq!(
+            index["docs"],
+            json!([
+                {"id": 0, "path": "/a.html", "title": "A", "body": "long body", "keywords": "x y"},
+                {"id": 1, "path": "/c.html", "title": "C"},
+                {"id": 2, "path": "/d.html", "title": "D"},
+            ])
+        );
+        let default = SearchIndex::new(["posts"]).build(results.as_object().unwrap());
+        assert_eq!(
+            default["fields"][0],
+            json!({"name": "title", "boost": 10.0})
+        );
+        assert_eq!(default["docs"][0]["body"], "long body text");
+    }
+
+    #[tokio::test]
+    async fn published_body() {
+        use crate::compiler::file::{FileReader, FileWriter};
+        let fs = crate::fs::MemoryFileSystem::new().with_file("site/posts/a.html", "<p>a</p>");
+        let build = |publish: Publish| {
+            Builder::new(Config::default().set_file_system(fs.clone()))
+                .add_step([Rule::new("posts", FileReader::new())
+                    .set_globs(["posts/*"])
+                    .set_publish(publish)])
+                .add_step([Rule::new(
+                    "search",
+                    pipe!(SearchIndex::new(["posts"]), FileWriter::new()),
+                )
+                .set_create(["search.json"])])
+                .build()
+        };
+        let docs = || {
+            let index: Value =
+                serde_json::from_str(&fs.get_string("dist/search.json").unwrap()).unwrap();
+            index["docs"].clone()
+        };
+        build(Publish::default()).await.unwrap();
+        assert_eq!(docs(), json!([{"id": 0, "path": "/posts/a.html"}]));
+        build(Publish::only(["_body"])).await.unwrap();
+        assert_eq!(
+            docs(),
+            json!([{"id": 0, "path": "/posts/a.html", "body": "a"}])
+        );
+    }
+}
@@ -0,0 +1,455 @@
+use crate::{builder::metadata::*, compiler::template::TemplateEngine, error::CodeFrame, *};
+use pulldown_cmark::{html::push_html, Event, Options, Parser};
+use serde::Serialize;
+use serde_json::{json, Map};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// A shortcode call found in the body.
+#[derive(Serialize, Clone, Debug)]
+pub struct Shortcode {
+    /// Shortcode name
+    pub name: String,
+    /// Named arguments, such as `id="..."`
+    pub args: Map<String, Value>,
+    /// Positional arguments
+    pub positional: Vec<Value>,
+    /// Expanded inner content of paired shortcode
+    pub inner: Option<String>,
+}
+
+/// Shortcode function type. The function takes a called [`Shortcode`] and page metadata, and
+/// returns expanded [`String`].
+pub type ShortcodeFn = Arc<dyn Fn(&Shortcode, &Value) -> Result<String, Error> + Send + Sync>;
+
+/// [`ShortcodeRenderer`] expands shortcodes in the body saved in [`BODY_META`], such as
+/// `{{< youtube id="..." >}}` and paired `{{% note %}}...{{% /note %}}`.
+///
+/// Each shortcode is expanded by a registered function or by rendering the template named
+/// `<template_dir>/<name>.html` with [`TemplateEngine`].
+/// Templates can use `args`, `positional`, `inner`, and page metadata as `page`.
+/// As in Hugo, the inner content of `{{% name %}}` is rendered as Markdown before it is passed to
+/// the shortcode, while the inner content of `{{< name >}}` is passed as it is.
+/// Shortcodes in code spans and code blocks are not expanded, so pages can document the syntax.
+/// Shortcodes can also be escaped as `{{</* name */>}}`, which is unescaped in code as well.
+///
+/// This may be used before [`MarkdownRenderer`][crate::compiler::markdown::MarkdownRenderer]
+/// and after [`FrontMatterParser`][crate::compiler::markdown::FrontMatterParser].
+#[derive(Clone)]
+pub struct ShortcodeRenderer {
+    engine: Option<(TemplateEngine, String)>,
+    functions: HashMap<String, ShortcodeFn>,
+    options: Options,
+}
+impl Default for ShortcodeRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShortcodeRenderer {
+    pub fn new() -> Self {
+        Self {
+            engine: None,
+            functions: HashMap::new(),
+            options: Options::empty(),
+        }
+    }
+    /// Expand shortcodes by rendering templates in the specified template directory.
+    pub fn templates(mut self, engine: TemplateEngine, template_dir: impl AsRef<str>) -> Self {
+        let dir = template_dir.as_ref().trim_end_matches('/').to_owned();
+        self.engine = Some((engine, dir));
+        self
+    }
+    /// Set Markdown options used to render the inner content of `{{% name %}}` shortcodes
+    pub fn markdown_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+    /// Register shortcode function. Registered functions take precedence over templates.
+    pub fn register(
+        mut self,
+        name: impl AsRef<str>,
+        f: impl Fn(&Shortcode, &Value) -> Result<String, Error> + Send + Sync + 'static,
+    ) -> Self {
+        self.functions.insert(name.as_ref().to_owned(), Arc::new(f));
+        self
+    }
+
+    /// Expand all shortcodes in the text
+    pub fn expand(&self, text: &str, page: &Value) -> Result<String, Error> {
+        let tokens = tokenize(text)?;
+        let mut index = 0;
+        let nodes = build_tree(text, &tokens, &mut index, None)?;
+        self.render_nodes(text, &nodes, page)
+    }
+
+    fn render_nodes(&self, text: &str, nodes: &[Node], page: &Value) -> Result<String, Error> {
+        let mut res = String::new();
+        for node in nodes {
+            match node {
+                Node::Text(s) => res.push_str(s),
+                Node::Shortcode {
+                    tag,
+                    children: inner,
+                } => {
+                    let inner = match inner {
+                        Some(children) => {
+                            let inner = self.render_nodes(text, children, page)?;
+                            if tag.markdown {
+                                let mut html = String::new();
+                                push_html(&mut html, Parser::new_ext(&inner, self.options));
+                                Some(html)
+                            } else {
+                                Some(inner)
+                            }
+                        }
+                        None => None,
+                    };
+                    let shortcode = Shortcode {
+                        name: tag.name.clone(),
+                        args: tag.args.clone(),
+                        positional: tag.positional.clone(),
+                        inner,
+                    };
+                    res.push_str(&self.render_shortcode(text, tag.offset, &shortcode, page)?);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn render_shortcode(
+        &self,
+        text: &str,
+        offset: usize,
+        shortcode: &Shortcode,
+        page: &Value,
+    ) -> Result<String, Error> {
+        if let Some(f) = self.functions.get(&shortcode.name) {
+            return f(shortcode, page);
+        }
+        if let Some((engine, dir)) = &self.engine {
+            let template = format!("{}/{}.html", dir, shortcode.name);
+            if engine.has_template(&template) {
+                let value = json!({
+                    "args": shortcode.args,
+                    "positional": shortcode.positional,
+                    "inner": shortcode.inner,
+                    "page": page,
+                });
+                let tera_ctx = tera::Context::from_value(value).map_err(Error::user_error)?;
+                return engine.render_context(&template, &tera_ctx);
+            }
+        }
+        Err(error_at(
+            text,
+            offset,
+            format!("unknown shortcode `{}`", shortcode.name),
+        ))
+    }
+}
+impl Compiler for ShortcodeRenderer {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let renderer = self.clone();
+        compile!({
+            let body = ctx
+                .body()
+                .await
+                .ok_or_else(|| Error::missing_metadata(BODY_META))?;
+            let body = body
+                .as_str()
+                .ok_or_else(|| Error::metadata_type(BODY_META, "a string"))?;
+            let page = Metadata::to_value(ctx.metadata().read_lock().await)?;
+            let expanded = renderer.expand(body, &page)?;
+            ctx.metadata_mut()
+                .insert_local(BODY_META.to_owned(), Value::String(expanded));
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Tag {
+    name: String,
+    args: Map<String, Value>,
+    positional: Vec<Value>,
+    self_closing: bool,
+    /// Opened by `{{%`, whose inner content is Markdown
+    markdown: bool,
+    offset: usize,
+}
+
+#[derive(Debug)]
+enum Token<'a> {
+    Text(&'a str),
+    Open(Tag),
+    Close { name: String, offset: usize },
+}
+
+#[derive(Debug)]
+enum Node<'a> {
+    Text(&'a str),
+    Shortcode {
+        tag: &'a Tag,
+        children: Option<Vec<Node<'a>>>,
+    },
+}
+
+fn error_at(text: &str, offset: usize, message: String) -> Error {
+    Error::syntax(message, Some(CodeFrame::new(None, text, offset)))
+}
+
+/// Byte ranges of Markdown code spans and code blocks
+fn code_ranges(text: &str) -> Vec<Range<usize>> {
+    Parser::new(text)
+        .into_offset_iter()
+        .filter_map(|(event, range)| match event {
+            Event::Code(_) | Event::Start(pulldown_cmark::Tag::CodeBlock(_)) => Some(range),
+            _ => None,
+        })
+        .collect()
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token<'_>>, Error> {
+    let code = code_ranges(text);
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = text[pos..].find("{{") {
+        let start = pos + found;
+        let delim = match text[start + 2..].chars().next() {
+            Some(c @ ('<' | '%')) => c,
+            _ => {
+                tokens.push(Token::Text(&text[pos..start + 2]));
+                pos = start + 2;
+                continue;
+            }
+        };
+        if pos < start {
+            tokens.push(Token::Text(&text[pos..start]));
+        }
+        // tags in code must be closed within the code
+        let code_end = code.iter().find(|r| r.contains(&start)).map(|r| r.end);
+        let in_code = code_end.is_some();
+        let end_pattern = if delim == '<' { ">}}" } else { "%}}" };
+        let content_start = start + 3;
+        let search_end = code_end.unwrap_or(text.len()).max(content_start);
+        let content_end = match text[content_start..search_end].find(end_pattern) {
+            Some(i) => content_start + i,
+            None if in_code => {
+                tokens.push(Token::Text(&text[start..content_start]));
+                pos = content_start;
+                continue;
+            }
+            None => return Err(error_at(text, start, "unclosed shortcode tag".to_owned())),
+        };
+        pos = content_end + end_pattern.len();
+        let content = text[content_start..content_end].trim();
+        // escaped shortcode, such as `{{</* name */>}}`
+        if let Some(escaped) = content
+            .strip_prefix("/*")
+            .and_then(|c| c.strip_suffix("*/"))
+        {
+            tokens.push(Token::Text(&text[start..start + 3]));
+            tokens.push(Token::Text(escaped));
+            tokens.push(Token::Text(&text[content_end..pos]));
+            continue;
+        }
+        if in_code {
+            tokens.push(Token::Text(&text[start..pos]));
+            continue;
+        }
+        if let Some(name) = content.strip_prefix('/') {
+            tokens.push(Token::Close {
+                name: name.trim().to_owned(),
+                offset: start,
+            });
+            continue;
+        }
+        let (content, self_closing) = match content.strip_suffix('/') {
+            Some(c) => (c.trim_end(), true),
+            None => (content, false),
+        };
+        let mut words = split_args(content).map_err(|m| error_at(text, start, m))?;
+        if words.is_empty() {
+            return Err(error_at(text, start, "empty shortcode tag".to_owned()));
+        }
+        let name = words.remove(0).1;
+        let mut args = Map::new();
+        let mut positional = Vec::new();
+        for (key, value) in words {
+            match key {
+                Some(key) => {
+                    args.insert(key, Value::String(value));
+                }
+                None => positional.push(Value::String(value)),
+            }
+        }
+        tokens.push(Token::Open(Tag {
+            name,
+            args,
+            positional,
+            self_closing,
+            markdown: delim == '%',
+            offset: start,
+        }));
+    }
+    if pos < text.len() {
+        tokens.push(Token::Text(&text[pos..]));
+    }
+    Ok(tokens)
+}
+
+/// Split shortcode tag content into `(key, value)` pairs. Positional arguments have no key.
+fn split_args(content: &str) -> Result<Vec<(Option<String>, String)>, String> {
+    let mut res = Vec::new();
+    let mut chars = content.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut key = None;
+        let mut word = String::new();
+        loop {
+            match chars.peek() {
+                Some('"') => {
+                    chars.next();
+                    let mut quoted = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\\') => {
+                                if let Some(c) = chars.next() {
+                                    quoted.push(c);
+                                }
+                            }
+                            Some('"') => break,
+                            Some(c) => quoted.push(c),
+                            None => return Err("unclosed quotation".to_owned()),
+                        }
+                    }
+                    word.push_str(&quoted);
+                }
+                Some('=') if key.is_none() => {
+                    chars.next();
+                    key = Some(std::mem::take(&mut word));
+                }
+                Some(c) if !c.is_whitespace() => {
+                    word.push(*c);
+                    chars.next();
+                }
+                _ => break,
+            }
+        }
+        res.push((key, word));
+    }
+    Ok(res)
+}
+
+fn build_tree<'a>(
+    text: &str,
+    tokens: &'a [Token<'a>],
+    index: &mut usize,
+    until: Option<&str>,
+) -> Result<Vec<Node<'a>>, Error> {
+    let mut nodes = Vec::new();
+    while let Some(token) = tokens.get(*index) {
+        *index += 1;
+        match token {
+            Token::Text(s) => nodes.push(Node::Text(s)),
+            Token::Open(tag) => {
+                let children = if !tag.self_closing && has_close(tokens, *index, &tag.name) {
+                    Some(build_tree(text, tokens, index, Some(&tag.name))?)
+                } else {
+                    None
+                };
+                nodes.push(Node::Shortcode { tag, children });
+            }
+            Token::Close { name, offset } => {
+                if Some(name.as_str()) == until {
+                    return Ok(nodes);
+                }
+                return Err(error_at(
+                    text,
+                    *offset,
+                    format!("unexpected closing shortcode `{}`", name),
+                ));
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// Check whether the shortcode opened just before `index` has its closing tag.
+fn has_close(tokens: &[Token], index: usize, name: &str) -> bool {
+    let mut depth = 0;
+    for token in &tokens[index..] {
+        match token {
+            Token::Open(tag) if tag.name == name && !tag.self_closing => depth += 1,
+            Token::Close { name: n, .. } if n == name => {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_shortcodes() {
+        let renderer = ShortcodeRenderer::new()
+            .register("youtube", |sc: &Shortcode, _: &Value| {
+                Ok(format!(
+                    "<iframe src=\"{}\"></iframe>",
+                    sc.args["id"].as_str().unwrap()
+                ))
+            })
+            .register("note", |sc: &Shortcode, page: &Value| {
+                Ok(format!(
+                    "<div title=\"{}\">{}</div>",
+                    page["title"].as_str().unwrap(),
+                    sc.inner.clone().unwrap_or_default()
+                ))
+            });
+        let page = json!({ "title": "page" });
+        let res = renderer
+            .expand(
+                "a {{< youtube id=\"x y\" >}} {{% note %}}in {{< youtube id=z />}}{{% /note %}} {{</* note */>}}",
+                &page,
+            )
+            .unwrap();
+        assert_eq!(
+            res,
+            "a <iframe src=\"x y\"></iframe> <div title=\"page\"><p>in <iframe src=\"z\"></iframe></p>\n</div> {{< note >}}"
+        );
+        assert_eq!(
+            renderer
+                .expand(
+                    "{{% note %}}*a*{{% /note %}}{{< note >}}*b*{{< /note >}}",
+                    &page
+                )
+                .unwrap(),
+            "<div title=\"page\"><p><em>a</em></p>\n</div><div title=\"page\">*b*</div>"
+        );
+        // shortcodes in code are kept, and escaped ones are unescaped
+        let doc = "`{{< unknown >}}` and `{{< note`\n\n```\n{{% note %}}\n{{</* note */>}}\n```\n";
+        assert_eq!(
+            renderer.expand(doc, &page).unwrap(),
+            "`{{< unknown >}}` and `{{< note`\n\n```\n{{% note %}}\n{{< note >}}\n```\n"
+        );
+        assert!(renderer.expand("{{< unknown >}}", &page).is_err());
+        assert!(renderer.expand("{{< note", &page).is_err());
+        assert!(renderer.expand("{{< /note >}}", &page).is_err());
+    }
+}
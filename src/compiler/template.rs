@@ -1,17 +1,51 @@
-use crate::{builder::metadata::BODY_META, *};
+use crate::{
+    builder::{
+        collection::Collection,
+        metadata::{BODY_LINE_META, BODY_META},
+    },
+    compiler::date::{value_to_date, TimeZone},
+    *,
+};
 use serde_json::Value;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tera::Tera;
 
 /// Template engine, which uses [`Tera`].
+///
+/// In addition to the built-in filters of Tera, the following filters using [`Collection`] are
+/// available for arrays of pages:
+/// - `sort_by(key, reverse=false)`: sort by the key, placing pages without the key last
+/// - `filter_by(key, value)`: keep pages whose value of the key equals to the value
+/// - `group_by_year(key)`, `group_by_month(key)`: group pages by the year or the month of the
+///   date, which returns an array of `{key, items}`
+///
+/// The built-in `date(format, timezone)` filter is replaced to also accept dates normalized by
+/// [`ParseDate`][crate::compiler::date::ParseDate], and dates such as `2024-01-19 02:53`.
+/// If `timezone` is specified, dates without offset are in `timezone`, and the result is
+/// converted into `timezone`. Otherwise the date is formatted in its own offset, which is
+/// [`Config::timezone`] for dates normalized by `ParseDate`, and dates without offset are
+/// formatted as they are. The default format is `%Y-%m-%d`.
+///
+/// ```text
+/// {% for year in posts | sort_by(key="date", reverse=true) | group_by_year(key="date") %}
+/// <h2>{{ year.key }}</h2>
+/// {% for post in year.items %}...{% endfor %}
+/// {% endfor %}
+/// ```
 #[derive(Clone)]
 pub struct TemplateEngine {
     tera: Tera,
+    /// Engine without templates, used to render one-off templates
+    one_off: Tera,
 }
 impl TemplateEngine {
     pub fn new(template_dir: impl AsRef<str>) -> Result<Self, Error> {
-        let tera = tera::Tera::new(template_dir.as_ref()).map_err(|err| Error::user_error(err))?;
-        Ok(Self { tera })
+        let mut tera = tera::Tera::new(template_dir.as_ref()).map_err(Error::user_error)?;
+        register_filters(&mut tera);
+        let mut one_off = Tera::default();
+        register_filters(&mut one_off);
+        Ok(Self { tera, one_off })
     }
 
     pub fn get(self) -> Arc<Self> {
@@ -24,12 +58,124 @@ impl TemplateEngine {
         template: impl AsRef<str>,
         metadata: &Metadata,
     ) -> Result<String, Error> {
-        let tera_ctx = tera::Context::from_serialize(metadata.read_lock().await)
-            .map_err(|err| Error::user_error(err))?;
+        let tera_ctx =
+            tera::Context::from_serialize(metadata.read_lock().await).map_err(Error::user_error)?;
         self.tera
             .render(template.as_ref(), &tera_ctx)
-            .map_err(|err| Error::user_error(err))
+            .map_err(Error::user_error)
     }
+
+    /// Render the template source as a one-off template named `name`, using metadata.
+    /// The name is used in error messages, so the source file path may be used.
+    /// The template may use the filters, but can not include, import or extend the templates of
+    /// this engine.
+    pub async fn render_str(
+        &self,
+        name: impl AsRef<str>,
+        source: impl AsRef<str>,
+        metadata: &Metadata,
+    ) -> Result<String, Error> {
+        let mut tera = self.one_off.clone();
+        tera.add_raw_template(name.as_ref(), source.as_ref())
+            .map_err(Error::user_error)?;
+        let tera_ctx =
+            tera::Context::from_serialize(metadata.read_lock().await).map_err(Error::user_error)?;
+        tera.render(name.as_ref(), &tera_ctx)
+            .map_err(Error::user_error)
+    }
+
+    /// Check whether the template exists
+    pub fn has_template(&self, template: impl AsRef<str>) -> bool {
+        self.tera
+            .get_template_names()
+            .any(|name| name == template.as_ref())
+    }
+
+    /// Render HTML using specified template and [`tera::Context`]
+    pub fn render_context(
+        &self,
+        template: impl AsRef<str>,
+        context: &tera::Context,
+    ) -> Result<String, Error> {
+        self.tera
+            .render(template.as_ref(), context)
+            .map_err(Error::user_error)
+    }
+}
+
+fn collection_arg(value: &Value, filter: &str) -> tera::Result<Collection> {
+    value
+        .as_array()
+        .map(|items| Collection::new(items.clone()))
+        .ok_or_else(|| tera::Error::msg(format!("`{}` filter requires an array", filter)))
+}
+
+fn key_arg<'a>(args: &'a HashMap<String, Value>, filter: &str) -> tera::Result<&'a str> {
+    args.get("key")
+        .and_then(|k| k.as_str())
+        .ok_or_else(|| tera::Error::msg(format!("`{}` filter requires `key` argument", filter)))
+}
+
+fn date_filter(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let format = args
+        .get("format")
+        .and_then(|f| f.as_str())
+        .unwrap_or("%Y-%m-%d");
+    let tz = match args.get("timezone").and_then(|t| t.as_str()) {
+        Some(tz) => Some(TimeZone::parse(tz).ok_or_else(|| {
+            tera::Error::msg(format!("`date` filter: invalid time zone `{}`", tz))
+        })?),
+        None => None,
+    };
+    let date = value_to_date(value, tz.as_ref().unwrap_or(&TimeZone::default()))
+        .ok_or_else(|| tera::Error::msg(format!("`date` filter: invalid date `{}`", value)))?;
+    let date = match tz {
+        Some(tz) => tz.convert(date),
+        None => date,
+    };
+    Ok(Value::from(date.format(format).to_string()))
+}
+
+fn register_filters(tera: &mut Tera) {
+    tera.register_filter("date", date_filter);
+    tera.register_filter("sort_by", |value: &Value, args: &HashMap<String, Value>| {
+        let items = collection_arg(value, "sort_by")?;
+        let key = key_arg(args, "sort_by")?;
+        let reverse = args.get("reverse").and_then(|r| r.as_bool()) == Some(true);
+        let items = if reverse {
+            items.sort_by_desc(key)
+        } else {
+            items.sort_by(key)
+        };
+        Ok(items.into())
+    });
+    tera.register_filter(
+        "filter_by",
+        |value: &Value, args: &HashMap<String, Value>| {
+            let items = collection_arg(value, "filter_by")?;
+            let key = key_arg(args, "filter_by")?;
+            let expected = args.get("value").cloned().unwrap_or(Value::Null);
+            Ok(items.filter_by(key, expected).into())
+        },
+    );
+    tera.register_filter(
+        "group_by_year",
+        |value: &Value, args: &HashMap<String, Value>| {
+            let items = collection_arg(value, "group_by_year")?;
+            let key = key_arg(args, "group_by_year")?;
+            let groups = items.group_by_year(key).into_iter().map(Value::from);
+            Ok(Value::Array(groups.collect()))
+        },
+    );
+    tera.register_filter(
+        "group_by_month",
+        |value: &Value, args: &HashMap<String, Value>| {
+            let items = collection_arg(value, "group_by_month")?;
+            let key = key_arg(args, "group_by_month")?;
+            let groups = items.group_by_month(key).into_iter().map(Value::from);
+            Ok(Value::Array(groups.collect()))
+        },
+    );
 }
 
 /// [`TemplateRenderer`] renders HTML using the specified template and [`Metadata`] in [`Context`].
@@ -51,10 +197,172 @@ impl Compiler for TemplateRenderer {
         let template = self.template.clone();
         compile!({
             let metadata = ctx.metadata();
-            let body = engine.render(&template, &metadata).await?;
+            let body = engine.render(&template, metadata).await?;
             ctx.metadata_mut()
                 .insert_local(BODY_META.to_owned(), Value::String(body));
             Ok(CompileStep::Completed(ctx))
         })
     }
 }
+
+/// [`BodyTemplateRenderer`] renders the body saved in [`BODY_META`] as a one-off template, using
+/// [`Metadata`] in [`Context`], and saves the result to [`BODY_META`].
+/// The template is named after the source file path, and syntax errors report the line in the
+/// source file using [`BODY_LINE_META`], so errors point at the source file.
+///
+/// This may be used after [`FrontMatterParser`][crate::compiler::markdown::FrontMatterParser]
+/// and [`WaitStage`][crate::compiler::utils::WaitStage], and before
+/// [`MarkdownRenderer`][crate::compiler::markdown::MarkdownRenderer], to loop over global
+/// metadata in Markdown bodies.
+#[derive(Clone)]
+pub struct BodyTemplateRenderer {
+    engine: TemplateEngine,
+    flag: Option<String>,
+}
+impl BodyTemplateRenderer {
+    pub fn new(engine: TemplateEngine) -> Self {
+        Self { engine, flag: None }
+    }
+    /// Render the body only if the specified metadata key, such as front matter flag, is `true`.
+    pub fn flag(mut self, key: impl AsRef<str>) -> Self {
+        self.flag = Some(key.as_ref().to_owned());
+        self
+    }
+}
+impl Compiler for BodyTemplateRenderer {
+    #[tracing::instrument(skip(self, ctx))]
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let engine = self.engine.clone();
+        let flag = self.flag.clone();
+        compile!({
+            if let Some(flag) = flag {
+                let enabled = ctx
+                    .metadata()
+                    .local()
+                    .get(&flag)
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                if !enabled {
+                    return Ok(CompileStep::Completed(ctx));
+                }
+            }
+            let body = ctx
+                .body()
+                .await
+                .ok_or_else(|| Error::missing_metadata(BODY_META))?;
+            let body = body
+                .as_str()
+                .ok_or_else(|| Error::metadata_type(BODY_META, "a string"))?;
+            let name = ctx
+                .source()
+                .await
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| BODY_META.to_owned());
+            // Pad the body with a comment, so that syntax errors report lines in the source file
+            let line = ctx
+                .metadata()
+                .local()
+                .get(BODY_LINE_META)
+                .and_then(|v| v.as_u64())
+                .unwrap_or(1);
+            let body = if line > 1 {
+                format!("{{#{}#}}{}", "\n".repeat(line as usize - 1), body)
+            } else {
+                body.to_owned()
+            };
+            let rendered = engine.render_str(name, body, ctx.metadata()).await?;
+            ctx.metadata_mut()
+                .insert_local(BODY_META.to_owned(), Value::String(rendered));
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn collection_filters() {
+        let engine = TemplateEngine::new("templates/**").unwrap();
+        let meta = Metadata::new();
+        meta.insert_global(
+            "posts".to_owned(),
+            json!([
+                {"title": "a", "date": "2023-12-31"},
+                {"title": "b", "date": "2024-02-01", "draft": true},
+                {"title": "c"},
+                {"title": "d", "date": "2024-01-15"},
+            ]),
+        )
+        .await;
+        let source = r#"{% for year in posts | sort_by(key="date", reverse=true) | group_by_year(key="date") -%}
+{{ year.key }}:{% for p in year.items %}{{ p.title }}{% endfor %};
+{%- endfor %}|{% for p in posts | filter_by(key="draft", value=true) %}{{ p.title }}{% endfor %}"#;
+        let rendered = engine.render_str("test", source, &meta).await.unwrap();
+        assert_eq!(rendered, "2024:bd;2023:a;:c;|b");
+    }
+
+    #[tokio::test]
+    async fn date_filter() {
+        let engine = TemplateEngine::new("templates/**").unwrap();
+        let mut meta = Metadata::new();
+        meta.insert_local(
+            "date".to_owned(),
+            json!({"iso": "2024-01-19T02:53:00+09:00", "timestamp": 1705600380}),
+        );
+        meta.insert_local("updated".to_owned(), json!("2024-01-19 02:53"));
+        let source = r#"{{ date | date }} {{ date | date(format="%H:%M", timezone="Asia/Tokyo") }} {{ updated | date(format="%d %H:%M", timezone="+09:00") }}"#;
+        let rendered = engine.render_str("test", source, &meta).await.unwrap();
+        assert_eq!(rendered, "2024-01-19 02:53 19 02:53");
+        // the date crosses midnight only when converted
+        let source = r#"{{ date | date(timezone="UTC") }} {{ date | date(format="%F %R") }} {{ updated | date(format="%F %R") }}"#;
+        let rendered = engine.render_str("test", source, &meta).await.unwrap();
+        assert_eq!(rendered, "2024-01-18 2024-01-19 02:53 2024-01-19 02:53");
+    }
+
+    #[tokio::test]
+    async fn body_template() {
+        use crate::compiler::{
+            file::{FileReader, FileWriter},
+            markdown::FrontMatterParser,
+        };
+        let fs = crate::fs::MemoryFileSystem::new()
+            .with_file(
+                "site/list.md",
+                "---\ntemplate: true\n---\n{% for n in names %}{{ n | upper }} {% endfor %}",
+            )
+            .with_file("site/raw.md", "{{ names }}")
+            .with_file("site/broken.md", "---\ntemplate: true\n---\n{{ missing }}")
+            .with_file(
+                "site/syntax.md",
+                "---\ntemplate: true\ntitle: syntax\n---\nline 5\n{% if %}\n",
+            );
+        let config = Config::default()
+            .set_file_system(fs.clone())
+            .set_param("names", ["a", "b"])
+            .unwrap();
+        let engine = TemplateEngine::new("templates/**").unwrap();
+        let compiler = pipe!(
+            FileReader::new(),
+            FrontMatterParser::new(),
+            BodyTemplateRenderer::new(engine).flag("template"),
+            FileWriter::new(),
+        );
+        let build = |glob: &str| {
+            Builder::new(config.clone())
+                .add_step([Rule::new("body", compiler.clone()).set_globs([glob])])
+                .build()
+        };
+        build("*[tw].md").await.unwrap();
+        assert_eq!(fs.get_string("dist/list.md").unwrap(), "A B ");
+        assert_eq!(fs.get_string("dist/raw.md").unwrap(), "{{ names }}");
+        let error = build("broken.md").await.unwrap_err();
+        assert!(format!("{:?}", error.cause()).contains("site/broken.md"));
+        let error = build("syntax.md").await.unwrap_err();
+        let message = format!("{:?}", error.cause());
+        assert!(message.contains("site/syntax.md"), "{}", message);
+        assert!(message.contains("--> 6:"), "{}", message);
+    }
+}
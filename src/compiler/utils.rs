@@ -1,23 +1,38 @@
-use crate::*;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::{
+    builder::{compile::panic_error, metadata::*},
+    *,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::task::JoinSet;
+use tracing_error::SpanTrace;
 
 /// Create compiler from closure
 impl<F> Compiler for F
 where
-    F: (Fn(Context) -> CompilerReturn) + Clone + Send + Sync,
+    F: (Fn(Context) -> CompilerReturn) + Clone + Send + Sync + 'static,
 {
     fn next_step(&mut self, ctx: Context) -> CompilerReturn {
         (self)(ctx)
     }
 }
 
-/// Wait for other tasks. This may be used to utilize intermediate results.
+/// Wait for other tasks of the same rule. This may be used to utilize intermediate results.
+///
+/// The tasks wait until all other tasks of the rule reach the same number of [`WaitStage`]s, or
+/// complete. Use [`Barrier`] to wait for tasks of other rules.
 #[derive(Clone)]
 pub struct WaitStage {
     steps: usize,
     current: usize,
 }
+impl Default for WaitStage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl WaitStage {
     pub fn new() -> Self {
         Self {
@@ -39,20 +54,90 @@ impl Compiler for WaitStage {
             compile!(Ok(CompileStep::Completed(ctx)))
         }
     }
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(Self::wait_steps(self.steps))
+    }
+}
+
+/// Wait at the named barrier until all tasks in the same build step which take part in it
+/// arrive. Unlike [`WaitStage`], the tasks may belong to different rules, and the number of
+/// steps before the barrier does not matter.
+///
+/// All tasks of the rules whose compilers contain the barrier take part in it. Tasks which
+/// complete or fail without arriving, such as by another branch of [`If`], leave it.
+/// If the waiting tasks can never be released, the build fails with [`Error::Deadlock`].
+///
+/// # Example
+/// ```
+/// use polysite::{compiler::{file::*, markdown::*, utils::Barrier}, *};
+/// let posts = pipe!(FileReader::new(), FrontMatterParser::new(), Barrier::new("parsed"));
+/// let pages = pipe!(FileReader::new(), Barrier::new("parsed"), FileWriter::new());
+/// Builder::new(Config::default()).add_step([
+///     Rule::new("posts", posts).set_globs(["posts/*.md"]),
+///     Rule::new("pages", pages).set_globs(["*.md"]),
+/// ]);
+/// ```
+#[derive(Clone)]
+pub struct Barrier {
+    name: String,
+    passed: bool,
+}
+impl Barrier {
+    pub fn new(name: impl AsRef<str>) -> Self {
+        Self {
+            name: name.as_ref().to_owned(),
+            passed: false,
+        }
+    }
+}
+impl Compiler for Barrier {
+    fn next_step(&mut self, ctx: Context) -> CompilerReturn {
+        if self.passed {
+            compile!(Ok(CompileStep::Completed(ctx)))
+        } else {
+            self.passed = true;
+            let name = self.name.clone();
+            compile!(Ok(CompileStep::WaitBarrier(name, ctx)))
+        }
+    }
+    fn barriers(&self) -> Vec<String> {
+        vec![self.name.clone()]
+    }
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(Self::new(&self.name))
+    }
 }
 
 /// Create a large compiler by piping multiple compilers.
 /// You may also use [`pipe!`] macro.
-#[derive(Clone)]
+///
+/// The pipeline holds the index of the running compiler. [`Compiler::fresh`] of the pipeline
+/// starts from the first compiler, with fresh instances of the compilers, so one pipeline can be
+/// reused for many tasks.
 pub struct PipeCompiler {
     compilers: Vec<Box<dyn Compiler>>,
-    ready: Option<Arc<RwLock<(usize, Vec<Box<dyn Compiler>>)>>>,
+    // shared only with the future of the running step
+    current: Arc<AtomicUsize>,
 }
+impl Default for PipeCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Clone for PipeCompiler {
+    fn clone(&self) -> Self {
+        Self {
+            compilers: self.compilers.clone(),
+            current: Arc::new(AtomicUsize::new(self.current.load(Ordering::Acquire))),
+        }
+    }
+}
+
 impl PipeCompiler {
     pub fn new() -> Self {
         Self {
             compilers: Vec::new(),
-            ready: None,
+            current: Arc::new(AtomicUsize::new(0)),
         }
     }
     /// Add the compiler to the end of the pipeline.
@@ -60,34 +145,449 @@ impl PipeCompiler {
         self.compilers.push(Box::new(compiler));
         self
     }
-    fn setup(&mut self) {
-        if self.ready.is_none() {
-            self.ready = Some(Arc::new(RwLock::new((0, self.compilers.clone()))));
+    /// Create the pipeline which starts from the first compiler. See [`Compiler::fresh`].
+    pub(crate) fn restart(&self) -> Self {
+        Self {
+            compilers: self.compilers.iter().map(|c| c.fresh()).collect(),
+            current: Arc::new(AtomicUsize::new(0)),
         }
     }
 }
 impl Compiler for PipeCompiler {
     fn next_step(&mut self, ctx: Context) -> CompilerReturn {
-        self.setup();
-        let ready = self.ready.clone().unwrap();
+        let index = self.current.load(Ordering::Acquire);
+        let len = self.compilers.len();
+        let Some(compiler) = self.compilers.get_mut(index) else {
+            return compile!(Ok(CompileStep::Completed(ctx)));
+        };
+        let step = compiler.next_step(ctx);
+        let current = self.current.clone();
         compile!({
-            let (ref mut current, ref mut compilers) = *ready.write().await;
-            if let Some(compiler) = compilers.get_mut(*current) {
-                let res = compiler.next_step(ctx).await?;
-                match res {
-                    CompileStep::Completed(ctx) => {
-                        *current += 1;
-                        if *current == compilers.len() {
-                            Ok(CompileStep::Completed(ctx))
-                        } else {
-                            Ok(CompileStep::InProgress(ctx))
-                        }
+            match step.await.map_err(|e| e.with_stage(index))? {
+                CompileStep::Completed(ctx) => {
+                    current.store(index + 1, Ordering::Release);
+                    if index + 1 == len {
+                        Ok(CompileStep::Completed(ctx))
+                    } else {
+                        Ok(CompileStep::InProgress(ctx))
                     }
-                    _ => Ok(res),
                 }
+                res => Ok(res),
+            }
+        })
+    }
+    fn barriers(&self) -> Vec<String> {
+        self.compilers.iter().flat_map(|c| c.barriers()).collect()
+    }
+    fn stage(&self) -> Vec<usize> {
+        let index = self.current.load(Ordering::Acquire);
+        let mut stage = vec![index];
+        if let Some(compiler) = self.compilers.get(index) {
+            stage.extend(compiler.stage());
+        }
+        stage
+    }
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(self.restart())
+    }
+}
+
+type Predicate = Arc<dyn Fn(&Context) -> bool + Send + Sync>;
+
+/// Stage of the chosen branch
+fn branch_stage(chosen: &Option<Option<Box<dyn Compiler>>>) -> Vec<usize> {
+    match chosen {
+        Some(Some(compiler)) => compiler.stage(),
+        _ => Vec::new(),
+    }
+}
+
+/// Run the branch, or complete the task if there is no branch
+fn run_branch(branch: &mut Option<Box<dyn Compiler>>, ctx: Context) -> CompilerReturn {
+    match branch {
+        Some(compiler) => compiler.next_step(ctx),
+        None => compile!(Ok(CompileStep::Completed(ctx))),
+    }
+}
+
+/// [`If`] runs `then` compiler if the predicate is `true`, otherwise `otherwise` compiler.
+/// The predicate is evaluated once, at the first step of the task.
+///
+/// # Example
+/// ```
+/// use polysite::{compiler::{template::*, utils::If}, *};
+/// let engine = TemplateEngine::new("templates/**").unwrap();
+/// If::new(
+///     |ctx: &Context| ctx.metadata().local().get("layout").and_then(|v| v.as_str()) == Some("post"),
+///     TemplateRenderer::new(engine.clone(), "post.html"),
+/// )
+/// .otherwise(TemplateRenderer::new(engine, "page.html"));
+/// ```
+#[derive(Clone)]
+pub struct If {
+    predicate: Predicate,
+    then: Box<dyn Compiler>,
+    otherwise: Option<Box<dyn Compiler>>,
+    chosen: Option<Option<Box<dyn Compiler>>>,
+}
+
+impl If {
+    pub fn new(
+        predicate: impl Fn(&Context) -> bool + Send + Sync + 'static,
+        then: impl Compiler + 'static,
+    ) -> Self {
+        Self {
+            predicate: Arc::new(predicate),
+            then: Box::new(then),
+            otherwise: None,
+            chosen: None,
+        }
+    }
+    /// Set the compiler used if the predicate is `false`. By default, nothing is done.
+    pub fn otherwise(mut self, compiler: impl Compiler + 'static) -> Self {
+        self.otherwise = Some(Box::new(compiler));
+        self
+    }
+}
+impl Compiler for If {
+    fn next_step(&mut self, ctx: Context) -> CompilerReturn {
+        let branch = self.chosen.get_or_insert_with(|| {
+            if (self.predicate)(&ctx) {
+                Some(self.then.fresh())
             } else {
-                Ok(CompileStep::Completed(ctx))
+                self.otherwise.as_ref().map(|c| c.fresh())
+            }
+        });
+        run_branch(branch, ctx)
+    }
+    fn barriers(&self) -> Vec<String> {
+        let mut barriers = self.then.barriers();
+        barriers.extend(self.otherwise.iter().flat_map(|c| c.barriers()));
+        barriers
+    }
+    fn stage(&self) -> Vec<usize> {
+        branch_stage(&self.chosen)
+    }
+    /// The predicate is evaluated again
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(Self {
+            predicate: self.predicate.clone(),
+            then: self.then.fresh(),
+            otherwise: self.otherwise.as_ref().map(|c| c.fresh()),
+            chosen: None,
+        })
+    }
+}
+
+#[derive(Clone)]
+enum MatchOn {
+    Extension,
+    Key(String),
+}
+
+/// [`Match`] selects the compiler by the extension of the source file, or by the value of the
+/// local metadata key, such as front matter `layout`. The value is matched once, at the first
+/// step of the task.
+///
+/// # Example
+/// ```
+/// use polysite::{compiler::{file::*, markdown::*, template::*, utils::Match}, *};
+/// let engine = TemplateEngine::new("templates/**").unwrap();
+/// Match::extension()
+///     .case("md", MarkdownCompiler::new(engine.clone(), "practical.html", None))
+///     .default(CopyCompiler::new());
+/// Match::key("layout")
+///     .case("post", TemplateRenderer::new(engine.clone(), "post.html"))
+///     .default(TemplateRenderer::new(engine, "page.html"));
+/// ```
+#[derive(Clone)]
+pub struct Match {
+    on: MatchOn,
+    cases: Vec<(Value, Box<dyn Compiler>)>,
+    default: Option<Box<dyn Compiler>>,
+    chosen: Option<Option<Box<dyn Compiler>>>,
+}
+
+impl Match {
+    /// Match the extension of the source file, which is compared case-insensitively
+    pub fn extension() -> Self {
+        Self::on(MatchOn::Extension)
+    }
+    /// Match the value of the local metadata key
+    pub fn key(key: impl AsRef<str>) -> Self {
+        Self::on(MatchOn::Key(key.as_ref().to_owned()))
+    }
+    fn on(on: MatchOn) -> Self {
+        Self {
+            on,
+            cases: Vec::new(),
+            default: None,
+            chosen: None,
+        }
+    }
+    /// Add the compiler used if the value equals to the specified value
+    pub fn case(mut self, value: impl Into<Value>, compiler: impl Compiler + 'static) -> Self {
+        self.cases.push((value.into(), Box::new(compiler)));
+        self
+    }
+    /// Set the compiler used if no case matches. By default, nothing is done.
+    pub fn default(mut self, compiler: impl Compiler + 'static) -> Self {
+        self.default = Some(Box::new(compiler));
+        self
+    }
+    fn select(&self, ctx: &Context) -> Option<Box<dyn Compiler>> {
+        let value = match &self.on {
+            MatchOn::Extension => ctx
+                .metadata()
+                .source()
+                .and_then(|s| s.extension().map(|e| e.to_string_lossy().to_lowercase()))
+                .map(Value::from),
+            MatchOn::Key(key) => ctx.metadata().local().get(key).cloned(),
+        };
+        let is_match = |case: &Value| match (&self.on, case, &value) {
+            (MatchOn::Extension, Value::String(case), Some(Value::String(ext))) => {
+                case.to_lowercase() == *ext
+            }
+            (_, case, value) => Some(case) == value.as_ref(),
+        };
+        self.cases
+            .iter()
+            .find(|(case, _)| is_match(case))
+            .map(|(_, compiler)| compiler.fresh())
+            .or_else(|| self.default.as_ref().map(|c| c.fresh()))
+    }
+}
+impl Compiler for Match {
+    fn next_step(&mut self, ctx: Context) -> CompilerReturn {
+        if self.chosen.is_none() {
+            self.chosen = Some(self.select(&ctx));
+        }
+        run_branch(self.chosen.as_mut().unwrap(), ctx)
+    }
+    fn barriers(&self) -> Vec<String> {
+        self.cases
+            .iter()
+            .map(|(_, c)| c)
+            .chain(&self.default)
+            .flat_map(|c| c.barriers())
+            .collect()
+    }
+    fn stage(&self) -> Vec<usize> {
+        branch_stage(&self.chosen)
+    }
+    /// The value is matched again
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(Self {
+            on: self.on.clone(),
+            cases: self
+                .cases
+                .iter()
+                .map(|(case, c)| (case.clone(), c.fresh()))
+                .collect(),
+            default: self.default.as_ref().map(|c| c.fresh()),
+            chosen: None,
+        })
+    }
+}
+
+type ErrorPredicate = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+/// [`Optional`] runs the compiler, and if it fails with the specified error, completes with the
+/// context before the failed step, instead of failing the task.
+///
+/// By default, [`Error::MissingMetadata`] is swallowed. The predicate receives the innermost
+/// error, without the stages of [`PipeCompiler`].
+#[derive(Clone)]
+pub struct Optional {
+    compiler: Box<dyn Compiler>,
+    predicate: ErrorPredicate,
+}
+
+impl Optional {
+    pub fn new(compiler: impl Compiler + 'static) -> Self {
+        Self {
+            compiler: Box::new(compiler),
+            predicate: Arc::new(|e| matches!(e, Error::MissingMetadata { .. })),
+        }
+    }
+    /// Set the predicate of errors to swallow
+    pub fn when(mut self, predicate: impl Fn(&Error) -> bool + Send + Sync + 'static) -> Self {
+        self.predicate = Arc::new(predicate);
+        self
+    }
+}
+impl Compiler for Optional {
+    fn next_step(&mut self, ctx: Context) -> CompilerReturn {
+        let saved = ctx.clone();
+        let step = self.compiler.next_step(ctx);
+        let predicate = self.predicate.clone();
+        compile!({
+            match step.await {
+                Err(error) if predicate(error.cause()) => {
+                    log::debug!("Optional compiler skipped: {}", error);
+                    Ok(CompileStep::Completed(saved))
+                }
+                res => res,
+            }
+        })
+    }
+    fn barriers(&self) -> Vec<String> {
+        self.compiler.barriers()
+    }
+    fn stage(&self) -> Vec<usize> {
+        self.compiler.stage()
+    }
+    fn fresh(&self) -> Box<dyn Compiler> {
+        Box::new(Self {
+            compiler: self.compiler.fresh(),
+            predicate: self.predicate.clone(),
+        })
+    }
+}
+
+/// [`Parallel`] runs multiple compilers concurrently on clones of the context, and applies the
+/// local metadata keys changed or removed by them.
+/// This may be used to fan out one source into multiple outputs, such as HTML and its summary.
+///
+/// All compilers run to completion within one step of the task, so they must not use
+/// [`WaitStage`]. Each changed key is replaced with the whole value of the compiler. If multiple
+/// compilers change the same key, the last added compiler wins.
+#[derive(Clone, Default)]
+pub struct Parallel {
+    compilers: Vec<Box<dyn Compiler>>,
+}
+
+impl Parallel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add the compiler run concurrently
+    pub fn add_compiler(mut self, compiler: impl Compiler + 'static) -> Self {
+        self.compilers.push(Box::new(compiler));
+        self
+    }
+}
+
+/// Run the compiler to completion within one step of the task
+async fn run_to_completion(
+    mut compiler: Box<dyn Compiler>,
+    mut ctx: Context,
+    combinator: &str,
+) -> Result<Context, Error> {
+    loop {
+        match compiler.next_step(ctx).await? {
+            CompileStep::Completed(c) => return Ok(c),
+            CompileStep::InProgress(c) => ctx = c,
+            CompileStep::WaitStage(_) | CompileStep::WaitBarrier(..) => {
+                return Err(Error::InvalidRule {
+                    trace: SpanTrace::capture(),
+                    message: format!(
+                        "`WaitStage` and `Barrier` are not supported in `{}`",
+                        combinator
+                    ),
+                })
+            }
+        }
+    }
+}
+
+impl Compiler for Parallel {
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let compilers: Vec<_> = self.compilers.iter().map(|c| c.fresh()).collect();
+        compile!({
+            let mut set = JoinSet::new();
+            for (i, compiler) in compilers.into_iter().enumerate() {
+                let branch = ctx.clone();
+                set.spawn(async move {
+                    (
+                        i,
+                        run_to_completion(compiler, branch, "Parallel")
+                            .await
+                            .map_err(|e| e.with_stage(i)),
+                    )
+                });
+            }
+            let mut results = Vec::new();
+            while let Some(res) = set.join_next().await {
+                let (i, res) = res.map_err(panic_error)?;
+                results.push((i, res?));
+            }
+            results.sort_by_key(|(i, _)| *i);
+            let base = ctx.metadata().clone();
+            let meta = ctx.metadata_mut();
+            for (_, branch) in results {
+                let changed = branch.metadata();
+                for key in base.local().keys() {
+                    if !changed.local().contains_key(key) {
+                        meta.remove_local(key);
+                    }
+                }
+                for (key, value) in changed.local() {
+                    match changed.get_bytes(key) {
+                        Some(b) if base.get_bytes(key) != Some(b) => {
+                            meta.insert_local_bytes(key.clone(), b.clone())
+                        }
+                        Some(_) => {}
+                        None if base.get_bytes(key).is_some()
+                            || base.local().get(key) != Some(value) =>
+                        {
+                            meta.insert_local(key.clone(), value.clone())
+                        }
+                        None => {}
+                    }
+                }
+            }
+            Ok(CompileStep::Completed(ctx))
+        })
+    }
+}
+
+/// [`Tee`] forks the pipeline. The compiler runs to completion on a clone of the context, and
+/// then the pipeline continues with the original context.
+/// If the fork writes its target, the target is added to [`OUTPUTS_META`].
+///
+/// This may be used to write another format of the same source after parsing, without another
+/// rule which reads and parses the source again.
+///
+/// # Example
+/// ```
+/// use polysite::{compiler::{file::*, markdown::*, path::*, template::*, utils::Tee}, *};
+/// let engine = TemplateEngine::new("templates/**").unwrap();
+/// pipe!(
+///     FileReader::new(),
+///     FrontMatterParser::new(),
+///     Tee::new(pipe!(SetExtension::new("md"), FileWriter::new())),
+///     SetExtension::new("html"),
+///     MarkdownRenderer::new(None),
+///     TemplateRenderer::new(engine, "practical.html"),
+///     FileWriter::new(),
+/// );
+/// ```
+#[derive(Clone)]
+pub struct Tee {
+    compiler: Box<dyn Compiler>,
+}
+
+impl Tee {
+    pub fn new(compiler: impl Compiler + 'static) -> Self {
+        Self {
+            compiler: Box::new(compiler),
+        }
+    }
+}
+impl Compiler for Tee {
+    fn next_step(&mut self, mut ctx: Context) -> CompilerReturn {
+        let compiler = self.compiler.fresh();
+        compile!({
+            let fork = run_to_completion(compiler, ctx.clone(), "Tee").await?;
+            let meta = fork.metadata();
+            if let (Some(target), Some(path)) = (meta.target(), meta.path()) {
+                let written = ctx.written().iter().any(|w| w.path == target);
+                if written && ctx.metadata().target() != Some(target.clone()) {
+                    ctx.add_output(&target, &path);
+                }
             }
+            Ok(CompileStep::Completed(ctx))
         })
     }
 }
@@ -116,3 +616,238 @@ macro_rules! pipe {
     }}
 }
 pub use pipe;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::{file::FileReader, markdown::FrontMatterParser, metadata::SetMetadata};
+    use crate::fs::MemoryFileSystem;
+    use std::path::Path;
+
+    fn set(key: &str, value: &str) -> SetMetadata {
+        SetMetadata::new().local(key, value).unwrap()
+    }
+
+    #[tokio::test]
+    async fn combinators() {
+        let fs = MemoryFileSystem::new()
+            .with_file("site/a.md", "---\nlayout: post\n---\na")
+            .with_file("site/b.MD", "b")
+            .with_file("site/c.txt", "c");
+        let missing = |ctx: Context| {
+            compile!({
+                ctx.metadata().get_as::<String>("missing").await?;
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let compiler = pipe!(
+            FileReader::new(),
+            Match::extension().case("md", FrontMatterParser::new()),
+            Match::key("layout")
+                .case("post", set("template", "post.html"))
+                .default(set("template", "page.html")),
+            If::new(
+                |ctx: &Context| ctx.metadata().source().unwrap().ends_with("c.txt"),
+                set("kind", "text"),
+            )
+            .otherwise(set("kind", "page")),
+            Optional::new(pipe!(set("optional", "set"), missing)),
+            Parallel::new()
+                .add_compiler(set("summary", "s"))
+                .add_compiler(pipe!(set("html", "h"), set("summary", "t"))),
+        );
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    let pages = &global["_versions"]["default"];
+                    let a = &pages["site/a.md"];
+                    assert_eq!(a["template"], "post.html");
+                    assert_eq!(a["kind"], "page");
+                    assert_eq!(a["html"], "h");
+                    assert_eq!(a["summary"], "t");
+                    assert_eq!(a["optional"], "set");
+                    assert_eq!(pages["site/b.MD"]["template"], "page.html");
+                    assert_eq!(pages["site/c.txt"]["kind"], "text");
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        Builder::new(Config::default().set_file_system(fs))
+            .add_step([Rule::new("pages", compiler).set_globs(["*"])])
+            .add_step([Rule::new("check", check).set_create(["check"])])
+            .build()
+            .await
+            .unwrap();
+
+        let failing = Optional::new(pipe!(set("a", "b"), missing)).when(|_| false);
+        let fs = MemoryFileSystem::new().with_file("site/a.txt", "a");
+        let res = Builder::new(Config::default().set_file_system(fs))
+            .add_step([Rule::new("fail", failing).set_globs(["*"])])
+            .build()
+            .await;
+        assert!(matches!(
+            res.unwrap_err().cause(),
+            Error::MissingMetadata { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn parallel_changes() {
+        let push = |ctx: Context| {
+            compile!({
+                let mut ctx = ctx;
+                let mut tags = ctx.metadata().get_as::<Vec<String>>("tags").await?;
+                tags.push("b".to_owned());
+                ctx.metadata_mut().insert_local_as("tags", tags)?;
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let undraft = |ctx: Context| {
+            compile!({
+                let mut ctx = ctx;
+                ctx.metadata_mut().remove_local("draft");
+                ctx.metadata_mut().insert_local_as("title", "a")?;
+                ctx.metadata_mut().insert_local_as("tags", ["a", "c"])?;
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let compiler = pipe!(
+            SetMetadata::new()
+                .local("tags", ["a"])
+                .unwrap()
+                .local("draft", true)
+                .unwrap(),
+            Parallel::new().add_compiler(push).add_compiler(undraft),
+        );
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    let page = &global["_versions"]["default"]["site/page"];
+                    // the whole array of the last compiler replaces the base array
+                    assert_eq!(page["tags"], serde_json::json!(["a", "c"]));
+                    assert_eq!(page["title"], "a");
+                    assert!(page.get("draft").is_none());
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let fs = MemoryFileSystem::new();
+        Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([Rule::new("page", compiler).set_create(["page"])])
+            .add_step([Rule::new("check", check).set_create(["check"])])
+            .build()
+            .await
+            .unwrap();
+
+        let waiting = Parallel::new().add_compiler(WaitStage::new());
+        let res = Builder::new(Config::default().set_file_system(fs))
+            .add_step([Rule::new("wait", waiting).set_create(["wait"])])
+            .build()
+            .await;
+        assert!(matches!(
+            res.unwrap_err().cause(),
+            Error::InvalidRule { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn multiple_outputs() {
+        use crate::compiler::{file::FileWriter, path::SetExtension};
+        let fs = MemoryFileSystem::new().with_file("site/posts/a.md", "a");
+        let emit = |ctx: Context| {
+            compile!({
+                ctx.emit_with_extension("json", r#"{"title":"a"}"#)?;
+                ctx.emit("./feed.xml", "<feed/>")?;
+                assert!(matches!(
+                    ctx.emit("/posts/../../escape.xml", "").unwrap_err(),
+                    Error::FileIo { .. }
+                ));
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    let outputs = &global["_versions"]["default"]["site/posts/a.md"]["_outputs"];
+                    let paths: Vec<_> = outputs
+                        .as_array()
+                        .unwrap()
+                        .iter()
+                        .map(|o| o["path"].as_str().unwrap())
+                        .collect();
+                    assert_eq!(paths, ["/posts/a.txt", "/posts/a.json", "/feed.xml"]);
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let compiler = pipe!(
+            FileReader::new(),
+            Tee::new(pipe!(SetExtension::new("txt"), FileWriter::new())),
+            emit,
+            FileWriter::new(),
+        );
+        let report = Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([Rule::new("posts", compiler).set_globs(["posts/*"])])
+            .add_step([Rule::new("check", check).set_create(["check"])])
+            .build()
+            .await
+            .unwrap();
+        for file in ["posts/a.md", "posts/a.txt", "posts/a.json", "feed.xml"] {
+            assert!(fs.get(Path::new("dist").join(file)).is_some(), "{}", file);
+        }
+        let task = report.compiled.iter().find(|t| t.rule == "posts").unwrap();
+        assert_eq!(task.written.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn pipe_state_per_task() {
+        let stage = |n: usize| set("stage", &n.to_string());
+        let mut pipe = pipe!(stage(1), stage(2));
+        let ctx = Context::new(Config::default());
+        let step = pipe.next_step(ctx.clone()).await.unwrap();
+        assert!(matches!(step, CompileStep::InProgress(_)));
+        assert_eq!(step.context().metadata().local()["stage"], "1");
+
+        // fresh pipeline starts from the first compiler
+        let mut fresh = pipe.fresh();
+        let step = fresh.next_step(ctx.clone()).await.unwrap();
+        assert_eq!(step.context().metadata().local()["stage"], "1");
+        // cloned pipeline keeps the state
+        let mut cloned = pipe.clone();
+        let step = cloned.next_step(ctx.clone()).await.unwrap();
+        assert_eq!(step.context().metadata().local()["stage"], "2");
+        let step = pipe.next_step(ctx).await.unwrap();
+        assert!(matches!(step, CompileStep::Completed(_)));
+        assert_eq!(step.context().metadata().local()["stage"], "2");
+    }
+
+    #[tokio::test]
+    async fn pipes_run_concurrently() {
+        let barrier = Arc::new(tokio::sync::Barrier::new(3));
+        let meet = move |ctx: Context| {
+            let barrier = barrier.clone();
+            compile!({
+                // deadlocks if the tasks share the pipeline
+                barrier.wait().await;
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let fs = MemoryFileSystem::new()
+            .with_file("site/a", "a")
+            .with_file("site/b", "b")
+            .with_file("site/c", "c");
+        let compiler = pipe!(FileReader::new(), pipe!(meet, set("done", "yes")));
+        let build = Builder::new(Config::default().set_file_system(fs))
+            .add_step([Rule::new("all", compiler).set_globs(["*"])])
+            .build();
+        let report = tokio::time::timeout(std::time::Duration::from_secs(10), build)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(report.compiled.len(), 3);
+        assert!(report.compiled.iter().all(|t| t.stages.len() == 3));
+    }
+}
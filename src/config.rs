@@ -1,14 +1,196 @@
-use serde::Deserialize;
-use std::path::PathBuf;
+use crate::{
+    compiler::date::{invalid_time_zone, TimeZone},
+    error::Error,
+    fs::{DiskFileSystem, FileSystem},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_error::SpanTrace;
 
+/// Environment variable to select the config profile.
+pub const PROFILE_ENV: &str = "POLYSITE_PROFILE";
+/// Prefix of environment variables that override config values.
+/// Nested keys are separated by `__`, such as `POLYSITE_PARAMS__AUTHOR`.
+pub const ENV_PREFIX: &str = "POLYSITE_";
+
+/// Site build configuration.
+///
+/// Site level fields and `params` are inserted into global [`Metadata`][crate::Metadata]:
+/// `title` as `site_title`, `base_url` as `site_url`, `language` as `site_language`, and each
+/// key of `params` as is.
 #[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
 pub struct Config {
     source_dir: PathBuf,
     target_dir: PathBuf,
     target_clean: bool,
+    keep_going: bool,
+    strict: bool,
+    drafts: bool,
+    future: bool,
+    expired: bool,
+    io_concurrency: usize,
+    exec_concurrency: Option<usize>,
+    max_tasks: Option<usize>,
+    max_tasks_per_rule: Option<usize>,
+    task_timeout: Option<f64>,
+    base_url: Option<String>,
+    title: Option<String>,
+    language: Option<String>,
+    timezone: Option<String>,
+    params: Map<String, Value>,
+    #[serde(skip, default = "default_file_system")]
+    file_system: Arc<dyn FileSystem>,
+}
+
+fn default_file_system() -> Arc<dyn FileSystem> {
+    Arc::new(DiskFileSystem)
+}
+
+/// Merge the profile into the config. Unlike
+/// [`merge_values`][crate::builder::metadata::merge_values], arrays are replaced.
+fn overlay(base: &mut Value, profile: Value) {
+    match (base, profile) {
+        (Value::Object(base), Value::Object(profile)) => {
+            for (key, value) in profile {
+                match base.get_mut(&key) {
+                    Some(left) => overlay(left, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, profile) => *base = profile,
+    }
+}
+
+/// Get the value overridden by the environment variable, whose nested keys are separated by `__`
+fn override_target<'a>(
+    value: &'a mut Value,
+    key: &str,
+    name: &str,
+) -> Result<&'a mut Value, Error> {
+    let mut target = value;
+    for k in key.split("__") {
+        target = target
+            .as_object_mut()
+            .ok_or(Error::InvalidConfig {
+                trace: SpanTrace::capture(),
+                message: format!("cannot override {}", name),
+            })?
+            .entry(k)
+            .or_insert(Value::Object(Map::new()));
+    }
+    Ok(target)
+}
+
+/// Fields of [`Config`] which are not strings, and are converted from environment variables
+const TYPED_FIELDS: &[&str] = &[
+    "target_clean",
+    "keep_going",
+    "strict",
+    "drafts",
+    "future",
+    "expired",
+    "io_concurrency",
+    "exec_concurrency",
+    "max_tasks",
+    "max_tasks_per_rule",
+    "task_timeout",
+];
+
+/// Convert the environment variable by the target field. Typed fields and params whose current
+/// value is a bool or number are parsed as such, and the others are kept as strings. The value
+/// which can not be parsed is kept as a string, and is reported when the config is deserialized.
+fn override_value(key: &str, current: &Value, v: String) -> Value {
+    let typed = TYPED_FIELDS.contains(&key)
+        || (key.starts_with("params__") && matches!(current, Value::Bool(_) | Value::Number(_)));
+    match serde_json::from_str(&v) {
+        Ok(parsed @ (Value::Bool(_) | Value::Number(_))) if typed => parsed,
+        _ => Value::String(v),
+    }
 }
 
 impl Config {
+    /// Load config from TOML, YAML or JSON file, which is detected by the file extension.
+    ///
+    /// The profile, such as `[profile.prod]`, selected by [`PROFILE_ENV`] is merged into the
+    /// config, and then environment variables starting with [`ENV_PREFIX`] override the values.
+    /// Tables of the profile are merged, and other values, including arrays, replace the values.
+    /// Environment variables are kept as strings, unless the field is a bool or number, such as
+    /// `POLYSITE_STRICT=false`, or the param is a bool or number in the config.
+    #[tracing::instrument]
+    pub fn from_file(path: impl AsRef<Path> + std::fmt::Debug) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|io_error| Error::FileIo {
+            trace: SpanTrace::capture(),
+            io_error,
+        })?;
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let value: Value = match ext.as_str() {
+            "toml" => toml::from_str(&text).map_err(Error::user_error)?,
+            "yaml" | "yml" => serde_yaml::from_str(&text).map_err(Error::user_error)?,
+            "json" => serde_json::from_str(&text).map_err(|serde_error| Error::SerdeJson {
+                trace: SpanTrace::capture(),
+                serde_error,
+            })?,
+            _ => {
+                return Err(Error::InvalidConfig {
+                    trace: SpanTrace::capture(),
+                    message: format!("unsupported config file: {}", path.display()),
+                })
+            }
+        };
+        let profile = std::env::var(PROFILE_ENV).ok();
+        Self::from_value(value, profile.as_deref(), std::env::vars())
+    }
+
+    /// Create config from [`Value`], merging the specified profile and applying overrides, which
+    /// are pairs of environment variable names and values.
+    #[tracing::instrument(skip(value, overrides))]
+    pub fn from_value(
+        mut value: Value,
+        profile: Option<&str>,
+        overrides: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Error> {
+        let root = value.as_object_mut().ok_or(Error::InvalidConfig {
+            trace: SpanTrace::capture(),
+            message: "config must be a table".to_owned(),
+        })?;
+        let mut profiles = root.remove("profile").unwrap_or_default();
+        if let Some(profile) = profile {
+            let selected =
+                profiles
+                    .get_mut(profile)
+                    .map(Value::take)
+                    .ok_or(Error::InvalidConfig {
+                        trace: SpanTrace::capture(),
+                        message: format!("profile `{}` not found", profile),
+                    })?;
+            overlay(&mut value, selected);
+        }
+        for (name, v) in overrides {
+            let key = match name.strip_prefix(ENV_PREFIX) {
+                Some(key) if name != PROFILE_ENV => key.to_lowercase(),
+                _ => continue,
+            };
+            let target = override_target(&mut value, &key, &name)?;
+            *target = override_value(&key, target, v);
+        }
+        serde_json::from_value(value).map_err(|serde_error| Error::SerdeJson {
+            trace: SpanTrace::capture(),
+            serde_error,
+        })
+    }
+
     /// Get source directory
     pub fn source_dir(&self) -> PathBuf {
         self.source_dir.clone()
@@ -36,6 +218,200 @@ impl Config {
         self.target_clean = clean;
         self
     }
+    /// Get keep going config
+    pub fn keep_going(&self) -> bool {
+        self.keep_going
+    }
+    /// Keep compiling the remaining files when a compilation task fails or panics.
+    /// Failures are collected into [`BuildReport`][crate::BuildReport]. The default is `false`,
+    /// which aborts the build on the first error.
+    pub fn set_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+    /// Get strict config
+    pub fn strict(&self) -> bool {
+        self.strict
+    }
+    /// If strict, [`Builder::build`][crate::Builder::build] which keeps going returns
+    /// [`Error::Build`] when some tasks failed, instead of the [`BuildReport`][crate::BuildReport].
+    /// The default is `true`.
+    pub fn set_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+    /// Get drafts config
+    pub fn drafts(&self) -> bool {
+        self.drafts
+    }
+    /// Build pages whose front matter has `draft: true`. The default is `false`.
+    pub fn set_drafts(mut self, drafts: bool) -> Self {
+        self.drafts = drafts;
+        self
+    }
+    /// Get future config
+    pub fn future(&self) -> bool {
+        self.future
+    }
+    /// Build pages whose front matter `date` is in the future. The default is `false`.
+    pub fn set_future(mut self, future: bool) -> Self {
+        self.future = future;
+        self
+    }
+    /// Get expired config
+    pub fn expired(&self) -> bool {
+        self.expired
+    }
+    /// Build pages whose front matter `expires` is in the past. The default is `false`.
+    pub fn set_expired(mut self, expired: bool) -> Self {
+        self.expired = expired;
+        self
+    }
+    /// Get the maximum number of concurrent file system operations
+    pub fn io_concurrency(&self) -> usize {
+        self.io_concurrency.max(1)
+    }
+    /// Set the maximum number of concurrent file system operations run by
+    /// [`Context::with_file_system`][crate::Context::with_file_system]. The default is `32`.
+    pub fn set_io_concurrency(mut self, limit: usize) -> Self {
+        self.io_concurrency = limit;
+        self
+    }
+    /// Get the maximum number of concurrently running external commands
+    pub fn exec_concurrency(&self) -> usize {
+        self.exec_concurrency
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+            .max(1)
+    }
+    /// Set the maximum number of concurrently running external commands run by
+    /// [`Exec`][crate::compiler::exec::Exec]. The default is the available parallelism.
+    pub fn set_exec_concurrency(mut self, limit: usize) -> Self {
+        self.exec_concurrency = Some(limit);
+        self
+    }
+    /// Get the maximum number of concurrently running compilation tasks
+    pub fn max_tasks(&self) -> Option<usize> {
+        self.max_tasks
+    }
+    /// Limit the number of concurrently running compilation tasks in the whole build.
+    /// The default is unlimited.
+    ///
+    /// Tasks waiting in [`WaitStage`][crate::compiler::utils::WaitStage] do not count, so the
+    /// limit does not deadlock tasks waiting for each other.
+    pub fn set_max_tasks(mut self, limit: usize) -> Self {
+        self.max_tasks = Some(limit.max(1));
+        self
+    }
+    /// Get the default maximum number of concurrently running compilation tasks of each rule
+    pub fn max_tasks_per_rule(&self) -> Option<usize> {
+        self.max_tasks_per_rule
+    }
+    /// Limit the number of concurrently running compilation tasks of each rule.
+    /// This may be overridden by [`Rule::set_max_tasks`][crate::Rule::set_max_tasks].
+    /// The default is unlimited.
+    pub fn set_max_tasks_per_rule(mut self, limit: usize) -> Self {
+        self.max_tasks_per_rule = Some(limit.max(1));
+        self
+    }
+    /// Get the timeout of each step of compilation tasks
+    pub fn task_timeout(&self) -> Option<Duration> {
+        self.task_timeout.map(Duration::from_secs_f64)
+    }
+    /// Fail the compilation task if one step of it, which is one call of
+    /// [`Compiler::next_step`][crate::Compiler::next_step], does not finish within the timeout.
+    /// Waiting for permits and for other tasks is not included. The default is no timeout.
+    ///
+    /// Set `task_timeout` in seconds in the config file.
+    pub fn set_task_timeout(mut self, timeout: Duration) -> Self {
+        self.task_timeout = Some(timeout.as_secs_f64());
+        self
+    }
+    /// Get site base URL
+    pub fn base_url(&self) -> Option<&str> {
+        self.base_url.as_deref()
+    }
+    /// Set site base URL
+    pub fn set_base_url(mut self, url: impl AsRef<str>) -> Self {
+        self.base_url = Some(url.as_ref().to_owned());
+        self
+    }
+    /// Get site title
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+    /// Set site title
+    pub fn set_title(mut self, title: impl AsRef<str>) -> Self {
+        self.title = Some(title.as_ref().to_owned());
+        self
+    }
+    /// Get site language
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+    /// Set site language
+    pub fn set_language(mut self, language: impl AsRef<str>) -> Self {
+        self.language = Some(language.as_ref().to_owned());
+        self
+    }
+    /// Get time zone of dates without offset
+    pub fn timezone(&self) -> Option<&str> {
+        self.timezone.as_deref()
+    }
+    /// Set time zone of dates without offset, which is a fixed offset such as `+09:00`, `UTC`, or
+    /// an IANA name such as `Asia/Tokyo`. The default is UTC.
+    pub fn set_timezone(mut self, timezone: impl AsRef<str>) -> Result<Self, Error> {
+        let timezone = timezone.as_ref();
+        TimeZone::parse(timezone).ok_or_else(|| invalid_time_zone(timezone))?;
+        self.timezone = Some(timezone.to_owned());
+        Ok(self)
+    }
+    /// Parse the time zone of dates without offset
+    pub fn parse_timezone(&self) -> Result<TimeZone, Error> {
+        match &self.timezone {
+            Some(timezone) => TimeZone::parse(timezone).ok_or_else(|| invalid_time_zone(timezone)),
+            None => Ok(TimeZone::default()),
+        }
+    }
+    /// Get site parameters
+    pub fn params(&self) -> &Map<String, Value> {
+        &self.params
+    }
+    /// Set site parameter
+    pub fn set_param(mut self, key: impl AsRef<str>, value: impl Serialize) -> Result<Self, Error> {
+        let value = serde_json::to_value(value).map_err(|serde_error| Error::SerdeJson {
+            trace: SpanTrace::capture(),
+            serde_error,
+        })?;
+        self.params.insert(key.as_ref().to_owned(), value);
+        Ok(self)
+    }
+
+    /// Get [`FileSystem`]
+    pub fn file_system(&self) -> Arc<dyn FileSystem> {
+        self.file_system.clone()
+    }
+    /// Set [`FileSystem`] used to read sources and write outputs. The default is
+    /// [`DiskFileSystem`].
+    pub fn set_file_system(mut self, file_system: impl FileSystem + 'static) -> Self {
+        self.file_system = Arc::new(file_system);
+        self
+    }
+
+    /// Get global metadata defined by this config
+    pub(crate) fn global_metadata(&self) -> Map<String, Value> {
+        let mut map = self.params.clone();
+        let site = [
+            ("site_title", &self.title),
+            ("site_url", &self.base_url),
+            ("site_language", &self.language),
+        ];
+        for (k, v) in site {
+            if let Some(v) = v {
+                map.insert(k.to_owned(), Value::from(v.clone()));
+            }
+        }
+        map
+    }
 }
 
 impl Default for Config {
@@ -44,6 +420,88 @@ impl Default for Config {
             source_dir: PathBuf::from("site"),
             target_dir: PathBuf::from("dist"),
             target_clean: true,
+            keep_going: false,
+            strict: true,
+            drafts: false,
+            future: false,
+            expired: false,
+            io_concurrency: 32,
+            exec_concurrency: None,
+            max_tasks: None,
+            max_tasks_per_rule: None,
+            task_timeout: None,
+            base_url: None,
+            title: None,
+            language: None,
+            timezone: None,
+            params: Map::new(),
+            file_system: default_file_system(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_profile_and_overrides() {
+        let value: Value = toml::from_str(
+            r#"
+            title = "site"
+            [params]
+            author = "a"
+            tags = ["a", "b"]
+            [profile.prod]
+            target_clean = false
+            base_url = "https://example.com"
+            [profile.prod.params]
+            tags = ["c"]
+            "#,
+        )
+        .unwrap();
+        let overrides = [
+            ("POLYSITE_TARGET_DIR".to_owned(), "public".to_owned()),
+            ("POLYSITE_PARAMS__AUTHOR".to_owned(), "b".to_owned()),
+            ("POLYSITE_PARAMS__YEAR".to_owned(), "2024".to_owned()),
+            ("POLYSITE_LANGUAGE".to_owned(), "true".to_owned()),
+            ("POLYSITE_STRICT".to_owned(), "false".to_owned()),
+            ("POLYSITE_MAX_TASKS".to_owned(), "4".to_owned()),
+            ("OTHER".to_owned(), "x".to_owned()),
+        ];
+        let config = Config::from_value(value.clone(), Some("prod"), overrides).unwrap();
+        assert_eq!(config.title(), Some("site"));
+        assert_eq!(config.base_url(), Some("https://example.com"));
+        assert!(!config.target_clean());
+        assert_eq!(config.target_dir(), PathBuf::from("public"));
+        assert_eq!(config.source_dir(), PathBuf::from("site"));
+        let global = config.global_metadata();
+        assert_eq!(global["author"], "b");
+        assert_eq!(global["site_title"], "site");
+        assert_eq!(global["tags"], serde_json::json!(["c"]));
+        assert_eq!(global["year"], "2024");
+        assert_eq!(config.language(), Some("true"));
+        assert!(!config.strict());
+        assert_eq!(config.max_tasks(), Some(4));
+        assert!(Config::from_value(value, Some("dev"), []).is_err());
+    }
+
+    #[test]
+    fn invalid_override() {
+        let value: Value = toml::from_str("[params]\ncount = 1\nname = \"a\"").unwrap();
+        let overrides = |concurrency: &str| {
+            [
+                ("POLYSITE_IO_CONCURRENCY".to_owned(), concurrency.to_owned()),
+                ("POLYSITE_PARAMS__NAME".to_owned(), "2".to_owned()),
+                ("POLYSITE_PARAMS__COUNT".to_owned(), "3".to_owned()),
+            ]
+        };
+        let config = Config::from_value(value.clone(), None, overrides("8")).unwrap();
+        assert_eq!(config.io_concurrency(), 8);
+        assert_eq!(config.params()["name"], "2");
+        assert_eq!(config.params()["count"], 3);
+        let error = Config::from_value(value, None, overrides("many")).unwrap_err();
+        assert!(matches!(error, Error::SerdeJson { .. }), "{:?}", error);
+        assert!(error.to_string().contains("many"), "{}", error);
+    }
+}
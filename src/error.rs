@@ -1,3 +1,8 @@
+use crate::builder::report::BuildReport;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
 use std::{error, fmt, io};
 use tracing_error::SpanTrace;
 
@@ -6,8 +11,37 @@ pub enum Error {
     InvalidMetadata {
         trace: SpanTrace,
     },
+    /// Required metadata key is missing.
+    MissingMetadata {
+        trace: SpanTrace,
+        key: String,
+    },
+    /// Metadata has an unexpected type.
+    MetadataType {
+        trace: SpanTrace,
+        key: String,
+        expected: String,
+    },
+    /// Rule or compiler is not configured correctly.
     InvalidRule {
         trace: SpanTrace,
+        message: String,
+    },
+    InvalidConfig {
+        trace: SpanTrace,
+        message: String,
+    },
+    /// Syntax error in the source, such as front matter or shortcode.
+    Syntax {
+        trace: SpanTrace,
+        message: String,
+        frame: Option<Box<CodeFrame>>,
+    },
+    /// Runtime error of the script run by a compiler, with the location in the script.
+    Script {
+        trace: SpanTrace,
+        message: String,
+        frame: Option<Box<CodeFrame>>,
     },
     SerdeJson {
         trace: SpanTrace,
@@ -21,6 +55,77 @@ pub enum Error {
         trace: SpanTrace,
         user_error: Box<dyn std::error::Error + Send + Sync>,
     },
+    /// Error occurred in a compilation task, with the rule name, the source file path, and the
+    /// stage indices of nested [`PipeCompiler`][crate::compiler::utils::PipeCompiler]s.
+    Compile {
+        rule: Option<String>,
+        source: Option<PathBuf>,
+        stage: Vec<usize>,
+        error: Box<Error>,
+    },
+    /// Tasks waiting at named [`Barrier`][crate::compiler::utils::Barrier]s can never be
+    /// released, or the barrier is not declared.
+    Deadlock {
+        trace: SpanTrace,
+        message: String,
+    },
+    /// External command exited with non-zero status.
+    Command {
+        trace: SpanTrace,
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// Compilation step did not finish within the timeout. The step is the index of the steps
+    /// of the task. This is wrapped in [`Error::Compile`] with the rule, the source file path,
+    /// and the stage of the running compiler.
+    Timeout {
+        timeout: Duration,
+        step: usize,
+    },
+    /// The build was cancelled by the cancellation token.
+    Cancelled,
+    /// Compilation task panicked.
+    Panic {
+        message: String,
+    },
+    /// Some compilation tasks failed in [`Builder::build`][crate::Builder::build] which continues
+    /// on error. The report lists all failures.
+    Build {
+        report: Box<BuildReport>,
+    },
+}
+
+/// Source code location and snippet of an error.
+#[derive(Debug, Clone)]
+pub struct CodeFrame {
+    pub file: Option<PathBuf>,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number
+    pub column: usize,
+    pub snippet: String,
+}
+
+impl CodeFrame {
+    /// Create code frame from text and byte offset of the error location.
+    pub fn new(file: Option<PathBuf>, text: &str, offset: usize) -> Self {
+        let offset = (0..=offset.min(text.len()))
+            .rev()
+            .find(|i| text.is_char_boundary(*i))
+            .unwrap_or(0);
+        let before = &text[..offset];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = text[line_start..offset].chars().count() + 1;
+        let snippet = text[line_start..].lines().next().unwrap_or("").to_owned();
+        Self {
+            file,
+            line,
+            column,
+            snippet,
+        }
+    }
 }
 
 impl Error {
@@ -30,44 +135,308 @@ impl Error {
             user_error: Box::new(error),
         }
     }
+    /// Create [`Error::MissingMetadata`]
+    pub fn missing_metadata(key: impl AsRef<str>) -> Self {
+        Self::MissingMetadata {
+            trace: SpanTrace::capture(),
+            key: key.as_ref().to_owned(),
+        }
+    }
+    /// Create [`Error::MetadataType`]
+    pub fn metadata_type(key: impl AsRef<str>, expected: impl AsRef<str>) -> Self {
+        Self::MetadataType {
+            trace: SpanTrace::capture(),
+            key: key.as_ref().to_owned(),
+            expected: expected.as_ref().to_owned(),
+        }
+    }
+    /// Create [`Error::Syntax`]
+    pub fn syntax(message: impl AsRef<str>, frame: Option<CodeFrame>) -> Self {
+        Self::Syntax {
+            trace: SpanTrace::capture(),
+            message: message.as_ref().to_owned(),
+            frame: frame.map(Box::new),
+        }
+    }
+    /// Create [`Error::Script`]
+    pub fn script(message: impl AsRef<str>, frame: Option<CodeFrame>) -> Self {
+        Self::Script {
+            trace: SpanTrace::capture(),
+            message: message.as_ref().to_owned(),
+            frame: frame.map(Box::new),
+        }
+    }
+
+    /// Add the stage index of [`PipeCompiler`][crate::compiler::utils::PipeCompiler].
+    pub fn with_stage(self, index: usize) -> Self {
+        match self {
+            Self::Compile {
+                rule,
+                source,
+                mut stage,
+                error,
+            } => {
+                stage.insert(0, index);
+                Self::Compile {
+                    rule,
+                    source,
+                    stage,
+                    error,
+                }
+            }
+            error => Self::Compile {
+                rule: None,
+                source: None,
+                stage: vec![index],
+                error: Box::new(error),
+            },
+        }
+    }
+    /// Add the rule name and the source file path of the compilation task.
+    pub fn with_task(self, rule: impl AsRef<str>, source: impl Into<PathBuf>) -> Self {
+        let (stage, error) = match self {
+            Self::Compile { stage, error, .. } => (stage, error),
+            error => (Vec::new(), Box::new(error)),
+        };
+        Self::Compile {
+            rule: Some(rule.as_ref().to_owned()),
+            source: Some(source.into()),
+            stage,
+            error,
+        }
+    }
+    /// Get the innermost error, without compilation task information.
+    pub fn cause(&self) -> &Error {
+        match self {
+            Self::Compile { error, .. } => error.cause(),
+            error => error,
+        }
+    }
+    /// Get human-friendly report of this error.
+    /// The report is colored if `NO_COLOR` is not set and stderr is a terminal.
+    pub fn report(&self) -> Report<'_> {
+        let color =
+            std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && io::stderr().is_terminal();
+        Report { error: self, color }
+    }
+
+    fn trace(&self) -> Option<&SpanTrace> {
+        match self {
+            Self::InvalidMetadata { trace }
+            | Self::MissingMetadata { trace, .. }
+            | Self::MetadataType { trace, .. }
+            | Self::InvalidRule { trace, .. }
+            | Self::InvalidConfig { trace, .. }
+            | Self::Syntax { trace, .. }
+            | Self::Script { trace, .. }
+            | Self::SerdeJson { trace, .. }
+            | Self::FileIo { trace, .. }
+            | Self::User { trace, .. }
+            | Self::Deadlock { trace, .. }
+            | Self::Command { trace, .. } => Some(trace),
+            Self::Compile { .. }
+            | Self::Timeout { .. }
+            | Self::Cancelled
+            | Self::Panic { .. }
+            | Self::Build { .. } => None,
+        }
+    }
+
+    /// Write the one line message of this error
+    fn message(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidMetadata { .. } => write!(f, "invalid metadata"),
+            Error::MissingMetadata { key, .. } => write!(f, "missing metadata `{}`", key),
+            Error::MetadataType { key, expected, .. } => {
+                write!(f, "metadata `{}` is not {}", key, expected)
+            }
+            Error::InvalidRule { message, .. } => write!(f, "invalid rule: {}", message),
+            Error::InvalidConfig { message, .. } => write!(f, "invalid config: {}", message),
+            Error::Syntax { message, .. } => write!(f, "syntax error: {}", message),
+            Error::Script { message, .. } => write!(f, "script error: {}", message),
+            Error::SerdeJson { serde_error, .. } => write!(f, "serde JSON failed: {}", serde_error),
+            Error::FileIo { io_error, .. } => write!(f, "file IO failed: {}", io_error),
+            Error::User { user_error, .. } => write!(f, "user error: {}", user_error),
+            Error::Compile { error, .. } => error.message(f),
+            Error::Deadlock { message, .. } => write!(f, "barrier deadlock: {}", message),
+            Error::Command {
+                command,
+                status,
+                stderr,
+                ..
+            } => {
+                write!(f, "command `{}` failed with {}", command, status)?;
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+            Error::Timeout { timeout, step } => write!(
+                f,
+                "step {} of the compilation task timed out after {:?}",
+                step, timeout
+            ),
+            Error::Cancelled => write!(f, "build cancelled"),
+            Error::Panic { message } => write!(f, "compilation task panicked: {}", message),
+            Error::Build { report } => write!(
+                f,
+                "{} of {} compilation tasks failed",
+                report.failed.len(),
+                report.failed.len() + report.compiled.len()
+            ),
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Report {
+            error: self,
+            color: false,
+        }
+        .fmt(f)
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
-            Error::InvalidMetadata { trace } => {
-                writeln!(f, "invalid metadata:")?;
-                trace.fmt(f)?;
-                Ok(())
-            }
-            Error::InvalidRule { trace } => {
-                writeln!(f, "invalid rule:")?;
-                trace.fmt(f)?;
-                Ok(())
+            Error::SerdeJson { serde_error, .. } => Some(serde_error),
+            Error::FileIo { io_error, .. } => Some(io_error),
+            Error::User { user_error, .. } => Some(user_error.as_ref()),
+            Error::Compile { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Human-friendly rendering of [`Error`], which is created by [`Error::report`].
+pub struct Report<'a> {
+    error: &'a Error,
+    color: bool,
+}
+
+impl Report<'_> {
+    /// Enable or disable colored output
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    fn paint(&self, code: &str, text: impl fmt::Display) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cause = self.error.cause();
+        write!(f, "{}: ", self.paint("1;31", "error"))?;
+        cause.message(f)?;
+        writeln!(f)?;
+        if let Error::Compile {
+            rule,
+            source,
+            stage,
+            ..
+        } = self.error
+        {
+            if let Some(rule) = rule {
+                writeln!(f, "  {} rule `{}`", self.paint("1;34", "in"), rule)?;
             }
-            Error::SerdeJson { trace, serde_error } => {
-                writeln!(f, "serde JSON failed:")?;
-                trace.fmt(f)?;
-                writeln!(f, "error cause:")?;
-                writeln!(f, "{:?}", serde_error)?;
-                Ok(())
+            if let Some(source) = source {
+                writeln!(f, "  {} {}", self.paint("1;34", "source"), source.display())?;
             }
-            Error::FileIo { trace, io_error } => {
-                writeln!(f, "file IO failed:")?;
-                trace.fmt(f)?;
-                writeln!(f, "error cause:")?;
-                writeln!(f, "{:?}", io_error)?;
-                Ok(())
+            if !stage.is_empty() {
+                let stage: Vec<_> = stage.iter().map(|s| s.to_string()).collect();
+                writeln!(f, "  {} {}", self.paint("1;34", "stage"), stage.join("."))?;
             }
-            Error::User { trace, user_error } => {
-                writeln!(f, "user error:")?;
-                trace.fmt(f)?;
-                writeln!(f, "error cause:")?;
-                writeln!(f, "{:?}", user_error)?;
-                Ok(())
+        }
+        if let Error::Syntax {
+            frame: Some(frame), ..
+        }
+        | Error::Script {
+            frame: Some(frame), ..
+        } = cause
+        {
+            let file = frame
+                .file
+                .as_ref()
+                .map(|f| f.display().to_string())
+                .unwrap_or_default();
+            let line = frame.line.to_string();
+            let pad = " ".repeat(line.len());
+            writeln!(
+                f,
+                "{}{} {}:{}:{}",
+                pad,
+                self.paint("1;34", "-->"),
+                file,
+                frame.line,
+                frame.column
+            )?;
+            writeln!(f, "{} {}", pad, self.paint("1;34", "|"))?;
+            writeln!(
+                f,
+                "{} {}",
+                self.paint("1;34", format!("{} |", line)),
+                frame.snippet
+            )?;
+            let marker_pad: String = frame
+                .snippet
+                .chars()
+                .take(frame.column.saturating_sub(1))
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            writeln!(
+                f,
+                "{} {} {}{}",
+                pad,
+                self.paint("1;34", "|"),
+                marker_pad,
+                self.paint("1;31", "^")
+            )?;
+        }
+        match cause {
+            Error::SerdeJson { serde_error, .. } => writeln!(f, "error cause:\n{:?}", serde_error)?,
+            Error::FileIo { io_error, .. } => writeln!(f, "error cause:\n{:?}", io_error)?,
+            Error::User { user_error, .. } => writeln!(f, "error cause:\n{:?}", user_error)?,
+            _ => {}
+        }
+        if let Some(trace) = cause.trace() {
+            trace.fmt(f)?;
+        }
+        if let Error::Build { report } = cause {
+            for failed in &report.failed {
+                writeln!(f)?;
+                failed.error.report().color(self.color).fmt(f)?;
             }
         }
+        Ok(())
     }
 }
 
-impl error::Error for Error {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_compile_error() {
+        let text = "---\ntitle: [oops\n---\n";
+        let error = Error::syntax("invalid front matter", Some(CodeFrame::new(None, text, 11)))
+            .with_stage(1)
+            .with_stage(2)
+            .with_task("posts", "site/posts/a.md");
+        assert!(matches!(error.cause(), Error::Syntax { .. }));
+        let report = error.report().color(false).to_string();
+        assert!(report.starts_with("error: syntax error: invalid front matter\n"));
+        assert!(report.contains("in rule `posts`"));
+        assert!(report.contains("source site/posts/a.md"));
+        assert!(report.contains("stage 2.1"));
+        assert!(report.contains("2 | title: [oops\n  |        ^"));
+    }
+}
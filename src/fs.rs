@@ -0,0 +1,275 @@
+//! File system abstraction used to read sources and write outputs.
+//!
+//! [`Config`][crate::Config] holds the [`FileSystem`], which is [`DiskFileSystem`] by default.
+//! [`MemoryFileSystem`] may be used to test compilers without temporary directories, or to
+//! render a site into memory.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+/// File system used by [`Builder`][crate::Builder], [`Rule`][crate::Rule] and
+/// [`Context`][crate::Context].
+pub trait FileSystem: Debug + Send + Sync {
+    /// Read the whole file
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+    /// Open the file to read it partially, such as only the front matter
+    fn open(&self, path: &Path) -> io::Result<Box<dyn io::BufRead + Send>> {
+        Ok(Box::new(io::Cursor::new(self.read(path)?)))
+    }
+    /// Write the whole file. The parent directory must exist.
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()>;
+    /// Copy the file, and returns the number of bytes copied
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        let data = self.read(from)?;
+        self.write(to, &data)?;
+        Ok(data.len() as u64)
+    }
+    /// Create the directory and all of its parents
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    /// Remove the directory and all of its contents
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()>;
+    fn is_file(&self, path: &Path) -> bool;
+    fn is_dir(&self, path: &Path) -> bool;
+    /// Get the last modification time of the file
+    fn modified(&self, path: &Path) -> io::Result<SystemTime>;
+    /// Find files matching the glob pattern, in sorted order
+    fn glob(&self, pattern: &str) -> io::Result<Vec<PathBuf>>;
+}
+
+fn invalid_pattern(error: glob::PatternError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, error)
+}
+
+/// [`FileSystem`] using [`std::fs`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiskFileSystem;
+
+impl FileSystem for DiskFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+    fn open(&self, path: &Path) -> io::Result<Box<dyn io::BufRead + Send>> {
+        Ok(Box::new(io::BufReader::new(std::fs::File::open(path)?)))
+    }
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        std::fs::write(path, data)
+    }
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<u64> {
+        std::fs::copy(from, to)
+    }
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_dir_all(path)
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        std::fs::metadata(path)?.modified()
+    }
+    fn glob(&self, pattern: &str) -> io::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+        for path in glob::glob(pattern).map_err(invalid_pattern)? {
+            let path = path.map_err(glob::GlobError::into_error)?;
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryFiles {
+    files: BTreeMap<PathBuf, (Vec<u8>, SystemTime)>,
+    dirs: BTreeSet<PathBuf>,
+}
+
+/// In-memory [`FileSystem`]. Clones share the same files, so outputs can be read after the build.
+///
+/// # Example
+/// ```
+/// use polysite::{fs::MemoryFileSystem, *};
+/// let fs = MemoryFileSystem::new().with_file("site/index.md", "# Hello");
+/// let config = Config::default().set_file_system(fs.clone());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem {
+    inner: Arc<RwLock<MemoryFiles>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Add the file, creating its parent directories
+    pub fn with_file(self, path: impl AsRef<Path>, data: impl AsRef<[u8]>) -> Self {
+        self.insert(path, data);
+        self
+    }
+    /// Insert the file, creating its parent directories
+    pub fn insert(&self, path: impl AsRef<Path>, data: impl AsRef<[u8]>) {
+        let path = path.as_ref();
+        let mut inner = self.inner.write().unwrap();
+        if let Some(parent) = path.parent() {
+            inner.dirs.extend(parent.ancestors().map(Path::to_path_buf));
+        }
+        inner
+            .files
+            .insert(path.to_owned(), (data.as_ref().to_vec(), SystemTime::now()));
+    }
+    /// Get the file data
+    pub fn get(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        self.inner
+            .read()
+            .unwrap()
+            .files
+            .get(path.as_ref())
+            .map(|(data, _)| data.clone())
+    }
+    /// Get the file data as string
+    pub fn get_string(&self, path: impl AsRef<Path>) -> Option<String> {
+        self.get(path).and_then(|data| String::from_utf8(data).ok())
+    }
+    /// List all file paths
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.inner.read().unwrap().files.keys().cloned().collect()
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found", path.display()),
+        )
+    }
+}
+
+impl FileSystem for MemoryFileSystem {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.get(path).ok_or_else(|| Self::not_found(path))
+    }
+    fn write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let parent = path.parent().filter(|p| !p.as_os_str().is_empty());
+        if let Some(parent) = parent {
+            if !self.is_dir(parent) {
+                return Err(Self::not_found(parent));
+            }
+        }
+        self.inner
+            .write()
+            .unwrap()
+            .files
+            .insert(path.to_owned(), (data.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.is_file(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} is a file", path.display()),
+            ));
+        }
+        self.inner
+            .write()
+            .unwrap()
+            .dirs
+            .extend(path.ancestors().map(Path::to_path_buf));
+        Ok(())
+    }
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        if !self.is_dir(path) {
+            return Err(Self::not_found(path));
+        }
+        let mut inner = self.inner.write().unwrap();
+        inner.files.retain(|p, _| !p.starts_with(path));
+        inner.dirs.retain(|p| !p.starts_with(path));
+        Ok(())
+    }
+    fn is_file(&self, path: &Path) -> bool {
+        self.inner.read().unwrap().files.contains_key(path)
+    }
+    fn is_dir(&self, path: &Path) -> bool {
+        self.inner.read().unwrap().dirs.contains(path)
+    }
+    fn modified(&self, path: &Path) -> io::Result<SystemTime> {
+        self.inner
+            .read()
+            .unwrap()
+            .files
+            .get(path)
+            .map(|(_, modified)| *modified)
+            .ok_or_else(|| Self::not_found(path))
+    }
+    fn glob(&self, pattern: &str) -> io::Result<Vec<PathBuf>> {
+        let pattern = glob::Pattern::new(pattern).map_err(invalid_pattern)?;
+        // same as walking directories, `*` does not match path separators
+        let options = glob::MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+        Ok(self
+            .inner
+            .read()
+            .unwrap()
+            .files
+            .keys()
+            .filter(|p| pattern.matches_path_with(p, options))
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::file::*, *};
+    use serde_json::Value;
+
+    #[tokio::test]
+    async fn build_in_memory() {
+        let fs = MemoryFileSystem::new()
+            .with_file("site/index.txt", "hello")
+            .with_file("site/posts/a.txt", "post")
+            .with_file("site/image.png", [0u8, 1, 2])
+            .with_file("dist/stale.txt", "stale");
+        let upper = |mut ctx: Context| {
+            compile!({
+                let body = ctx.body().await.unwrap();
+                let body = body.as_str().unwrap().to_uppercase();
+                ctx.metadata_mut()
+                    .insert_local("_body".to_owned(), Value::from(body));
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let report = Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([
+                Rule::new("text", pipe!(FileReader::new(), upper, FileWriter::new()))
+                    .set_globs(["**/*.txt"]),
+                Rule::new("others", CopyCompiler::new()).set_globs(["**/*"]),
+            ])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(report.compiled.len(), 3);
+        assert_eq!(fs.get_string("dist/index.txt").unwrap(), "HELLO");
+        assert_eq!(fs.get_string("dist/posts/a.txt").unwrap(), "POST");
+        assert_eq!(fs.get("dist/image.png").unwrap(), vec![0, 1, 2]);
+        assert!(!fs.is_file(Path::new("dist/stale.txt")));
+        assert_eq!(
+            fs.glob("site/*").unwrap(),
+            vec![
+                PathBuf::from("site/image.png"),
+                PathBuf::from("site/index.txt")
+            ]
+        );
+    }
+}
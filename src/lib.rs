@@ -10,6 +10,7 @@
 //! # How to use
 //! If you would like to simply build site written in Markdown, use [`compiler::markdown::MarkdownCompiler`].
 //! The example is in [`examples/simple_markdown.rs`][simple_example].
+//! To convert sources by external tools, such as pandoc, use [`compiler::exec::Exec`].
 //!
 //! # How to create compiler
 //! If you would like to create a new compiler, implement [`Compiler`] trait for your type.
@@ -30,10 +31,20 @@
 //! - [`_source`][builder::metadata::SOURCE_FILE_META]: source file path
 //! - [`_target`][builder::metadata::TARGET_FILE_META]: target file path
 //! - [`_path`][builder::metadata::PATH_META]: absolute URL path
-//! - [`_body`][builder::metadata::BODY_META]: Content body. For the result of each compilation task.
+//! - [`_outputs`][builder::metadata::OUTPUTS_META]: additional output files emitted by [`Context::emit`]
+//! - [`_body`][builder::metadata::BODY_META]: Content body. For the result of each compilation task. Binary body is held out-of-band by [`Metadata::insert_local_bytes`].
+//! - [`_body_line`][builder::metadata::BODY_LINE_META]: line number in the source file at which the body begins after front matter
 //!
 //! You can use these default key of [`Metadata`] to create new compiler.
 //!
+//! # Config
+//! [`Config`] can be loaded from TOML, YAML or JSON file, such as `polysite.toml`, using [`Config::from_file`].
+//! Site level fields and `[params]` in the config are inserted into global [`Metadata`].
+//!
+//! # File system
+//! Sources are read and outputs are written through [`fs::FileSystem`] set in [`Config`].
+//! [`fs::MemoryFileSystem`] may be used to build a site in memory.
+//!
 //! # Example
 //! Practical example is here.
 //! Other examples are in [repository][examples].
@@ -94,13 +105,16 @@ pub mod builder;
 pub mod compiler;
 pub mod config;
 pub mod error;
+pub mod fs;
 
 #[doc(inline)]
 pub use builder::{
     builder::Builder,
     context::{Context, Version},
     metadata::Metadata,
-    rule::Rule,
+    plan::BuildPlan,
+    report::BuildReport,
+    rule::{Publish, Rule},
 };
 #[doc(inline)]
 pub use compiler::{CompileResult, CompileStep, Compiler, CompilerReturn};
@@ -113,6 +127,13 @@ pub use error::Error;
 mod tests {
     use super::*;
 
+    /// Get a directory path unique to the test process and the call, which does not exist yet
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        static COUNTER: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        std::env::temp_dir().join(format!("polysite-{}-{}-{}", name, std::process::id(), n))
+    }
+
     #[derive(Clone)]
     struct PrintCompiler;
     impl PrintCompiler {
@@ -133,7 +154,10 @@ mod tests {
 
     #[tokio::test]
     async fn build_site() {
-        let config = Config::default().set_source_dir("src");
+        let target_dir = temp_dir("build-site");
+        let config = Config::default()
+            .set_source_dir("src")
+            .set_target_dir(&target_dir);
         let builder = Builder::new(config);
         let result = builder
             // Add one rule as build step
@@ -165,5 +189,341 @@ mod tests {
             .await;
         println!("{:?}", result);
         assert!(result.is_ok());
+        let _ = std::fs::remove_dir_all(target_dir);
+    }
+
+    #[tokio::test]
+    async fn keep_going_build() {
+        let fail = |ctx: Context| {
+            compile!({
+                let src = ctx.source().await.unwrap();
+                match src.file_name().unwrap().to_str().unwrap() {
+                    "rule.rs" => Err(Error::missing_metadata("title")),
+                    "compile.rs" => panic!("broken compiler"),
+                    _ => Ok(CompileStep::Completed(ctx)),
+                }
+            })
+        };
+        let build = |config: Config| {
+            Builder::new(config.set_source_dir("src").set_target_clean(false))
+                .add_step([Rule::new("fail", fail).set_globs(["builder/*.rs"])])
+                .build()
+        };
+        let error = build(Config::default().set_keep_going(true))
+            .await
+            .unwrap_err();
+        let Error::Build { report } = error else {
+            panic!("unexpected error: {}", error);
+        };
+        assert_eq!(report.failed.len(), 2);
+        assert!(report
+            .failed
+            .iter()
+            .any(|f| matches!(f.error.cause(), Error::Panic { .. })));
+        assert!(!report.compiled.is_empty());
+
+        let report = build(Config::default().set_keep_going(true).set_strict(false))
+            .await
+            .unwrap();
+        assert_eq!(report.failed.len(), 2);
+
+        assert!(build(Config::default()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn build_report() {
+        let target_dir = temp_dir("build-report");
+        let config = Config::default()
+            .set_source_dir("src")
+            .set_target_dir(&target_dir);
+        let report = Builder::new(config)
+            .add_step([Rule::new(
+                "copy",
+                pipe!(
+                    compiler::utils::WaitStage::new(),
+                    compiler::file::CopyCompiler::new()
+                ),
+            )
+            .set_globs(["builder/*.rs"])])
+            .add_step([
+                Rule::new("again", compiler::file::CopyCompiler::new()).set_globs(["builder/*.rs"])
+            ])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(report.rules.len(), 2);
+        assert_eq!(report.rules[1].step, 1);
+        assert_eq!(report.rules[0].matched.len(), report.rules[1].matched.len());
+        assert_eq!(report.skipped.len(), report.rules[1].matched.len());
+        let task = &report.compiled[0];
+        assert_eq!(task.stages.len(), 3);
+        assert_eq!(task.written.len(), 1);
+        assert_eq!(
+            task.written[0].bytes,
+            std::fs::metadata(&task.source).unwrap().len()
+        );
+        assert!(report.to_json().is_ok());
+        let trace = report.to_chrome_trace();
+        assert!(trace["traceEvents"].as_array().unwrap().len() > report.compiled.len());
+        std::fs::remove_dir_all(target_dir).unwrap();
+    }
+
+    #[test]
+    fn plan_site() {
+        let target_dir = temp_dir("plan");
+        let config = Config::default()
+            .set_source_dir("src")
+            .set_target_dir(&target_dir);
+        let builder = Builder::new(config)
+            .add_step([Rule::new("builder", PrintCompiler::new()).set_globs(["builder/*.rs"])])
+            .add_step([
+                Rule::new("compiler", PrintCompiler::new()).set_globs(["compiler/*.rs"]),
+                Rule::new("rest", PrintCompiler::new()).set_globs(["**/*.rs"]),
+                Rule::new("none", PrintCompiler::new()).set_globs(["nothing/*"]),
+            ]);
+        let plan = builder.plan().unwrap();
+        assert!(!target_dir.exists());
+        let rule_of = |source: &str| {
+            plan.tasks
+                .iter()
+                .find(|t| t.source == std::path::Path::new("src").join(source))
+                .map(|t| t.rule.as_str())
+        };
+        assert_eq!(rule_of("builder/rule.rs"), Some("builder"));
+        assert_eq!(rule_of("compiler/utils.rs"), Some("compiler"));
+        assert_eq!(rule_of("lib.rs"), Some("rest"));
+        let task = plan.tasks.iter().find(|t| t.rule == "rest").unwrap();
+        assert_eq!(
+            task.target,
+            target_dir.join(task.path.strip_prefix("/").unwrap())
+        );
+        assert!(plan.skipped.iter().all(|s| s.rule == "rest"));
+        assert!(plan.unmatched.is_empty());
+        assert_eq!(plan.unused_rules.len(), 1);
+        assert_eq!(plan.unused_rules[0].rule, "none");
+        assert_eq!(plan.unused_rules[0].matched, 0);
+
+        let plan = Builder::new(Config::default().set_source_dir("src"))
+            .add_step([Rule::new("lib", PrintCompiler::new()).set_create(["lib.rs"])])
+            .plan()
+            .unwrap();
+        assert!(plan.unmatched.iter().any(|p| p.ends_with("config.rs")));
+        assert!(!plan.is_clean());
+    }
+
+    #[tokio::test]
+    async fn bounded_concurrency() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let running = Arc::new(AtomicUsize::new(0));
+        let max = Arc::new(AtomicUsize::new(0));
+        let count = {
+            let (running, max) = (running.clone(), max.clone());
+            move |ctx: Context| {
+                let (running, max) = (running.clone(), max.clone());
+                compile!({
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max.fetch_max(now, Ordering::SeqCst);
+                    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(CompileStep::Completed(ctx))
+                })
+            }
+        };
+        let config = Config::default()
+            .set_source_dir("src")
+            .set_target_clean(false)
+            .set_max_tasks(3);
+        let report = Builder::new(config)
+            .add_step([
+                Rule::new(
+                    "wait",
+                    pipe!(
+                        count.clone(),
+                        compiler::utils::WaitStage::new(),
+                        count.clone()
+                    ),
+                )
+                .set_globs(["**/*.rs"])
+                .set_max_tasks(2),
+                Rule::new("other", count.clone())
+                    .set_globs(["**/*.rs"])
+                    .set_version("other"),
+            ])
+            .build()
+            .await
+            .unwrap();
+        assert!(report.compiled.len() > 6);
+        assert!(max.load(Ordering::SeqCst) <= 3);
+        assert!(report
+            .compiled
+            .iter()
+            .filter(|t| t.rule == "wait")
+            .all(|t| t.stages.len() == 4));
+    }
+
+    #[tokio::test]
+    async fn publish_keys() {
+        let fs = fs::MemoryFileSystem::new()
+            .with_file("site/a.txt", "a")
+            .with_file("site/b.md", "b");
+        let read = pipe!(
+            compiler::file::FileReader::new(),
+            compiler::metadata::SetMetadata::new()
+                .local("title", "t")
+                .unwrap()
+                .local("draft", false)
+                .unwrap(),
+        );
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    let txt = &global["txt"][0];
+                    assert!(txt.get("_body").is_none());
+                    assert_eq!(txt["title"], "t");
+                    let md = &global["md"][0];
+                    assert_eq!(md["_body"], "b");
+                    assert!(md.get("draft").is_none());
+                    assert_eq!(md["_path"], "/b.md");
+                    let versions = &global["_versions"]["default"];
+                    assert!(versions["site/a.txt"].get("_body").is_none());
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        Builder::new(Config::default().set_file_system(fs))
+            .add_step([
+                Rule::new("txt", read.clone()).set_globs(["*.txt"]),
+                Rule::new("md", read)
+                    .set_globs(["*.md"])
+                    .set_publish(Publish::only(["title", "_body"])),
+            ])
+            .add_step([Rule::new("check", check).set_create(["check"])])
+            .build()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn publication_controls() {
+        let fs = fs::MemoryFileSystem::new()
+            .with_file("site/posts/a.md", "---\ntitle: a\n---\n")
+            .with_file("site/posts/draft.md", "---\ndraft: true\n---\n")
+            .with_file("site/posts/future.md", "---\ndate: 2999-01-01\n---\n")
+            .with_file("site/posts/expired.md", "---\nexpires: 2000-01-01\n---\n")
+            .with_file("site/posts/notes.txt", "---\ndraft: true\n---\n");
+        let check = |ctx: Context| {
+            compile!({
+                {
+                    let global = ctx.metadata().global().await;
+                    assert_eq!(global["posts"].as_array().unwrap().len(), 2);
+                    let versions = global["_versions"]["default"].as_object().unwrap();
+                    assert!(versions.contains_key("site/posts/a.md"));
+                    assert!(!versions.contains_key("site/posts/draft.md"));
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let report = Builder::new(Config::default().set_file_system(fs.clone()))
+            .add_step([
+                Rule::new("posts", compiler::file::CopyCompiler::new()).set_globs(["posts/*"]),
+                Rule::new("rest", compiler::file::CopyCompiler::new()).set_globs(["**/*"]),
+            ])
+            .add_step([Rule::new("check", check).set_create(["check"])])
+            .build()
+            .await
+            .unwrap();
+        let reason = |name: &str| {
+            report
+                .skipped
+                .iter()
+                .find(|s| s.rule == "posts" && s.source.ends_with(name))
+                .map(|s| s.reason)
+        };
+        assert_eq!(reason("draft.md"), Some(builder::report::SkipReason::Draft));
+        assert_eq!(
+            reason("future.md"),
+            Some(builder::report::SkipReason::Future)
+        );
+        assert_eq!(
+            reason("expired.md"),
+            Some(builder::report::SkipReason::Expired)
+        );
+        assert_eq!(reason("notes.txt"), None);
+        assert!(fs.get("dist/posts/a.md").is_some());
+        assert!(fs.get("dist/posts/draft.md").is_none());
+
+        let plan = Builder::new(
+            Config::default()
+                .set_file_system(fs)
+                .set_drafts(true)
+                .set_future(true),
+        )
+        .add_step([Rule::new("posts", compiler::file::CopyCompiler::new())
+            .set_globs(["posts/*"])
+            .set_publication_checks(true)])
+        .plan()
+        .unwrap();
+        assert_eq!(plan.tasks.len(), 4);
+        assert_eq!(plan.skipped[0].reason, builder::report::SkipReason::Expired);
+    }
+
+    #[tokio::test]
+    async fn timeout_and_cancel() {
+        let fs = fs::MemoryFileSystem::new()
+            .with_file("site/a", "")
+            .with_file("site/b", "");
+        let hang = |ctx: Context| {
+            compile!({
+                if ctx.metadata().source().unwrap().ends_with("b") {
+                    tokio::time::sleep(std::time::Duration::from_secs(3600)).await;
+                }
+                Ok(CompileStep::Completed(ctx))
+            })
+        };
+        let config = Config::default()
+            .set_file_system(fs)
+            .set_task_timeout(std::time::Duration::from_millis(50));
+        let error = Builder::new(config.clone())
+            .add_step([Rule::new(
+                "hang",
+                pipe!(
+                    compiler::file::CopyCompiler::new(),
+                    pipe!(compiler::metadata::SetMetadata::new(), hang),
+                ),
+            )
+            .set_globs(["*"])])
+            .build()
+            .await
+            .unwrap_err();
+        assert!(matches!(error.cause(), Error::Timeout { step: 2, .. }));
+        let message = error.to_string();
+        assert!(message.contains("in rule `hang`"), "{}", message);
+        assert!(message.contains("source site/b"), "{}", message);
+        assert!(message.contains("stage 1.1"), "{}", message);
+
+        // the rule timeout overrides the global one
+        let report = Builder::new(config.clone().set_keep_going(true).set_strict(false))
+            .add_step([Rule::new("hang", hang)
+                .set_globs(["*"])
+                .set_timeout(std::time::Duration::from_millis(10))])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!((report.compiled.len(), report.failed.len()), (1, 1));
+
+        let token = tokio_util::sync::CancellationToken::new();
+        let build = Builder::new(config.set_task_timeout(std::time::Duration::from_secs(3600)))
+            .set_cancellation_token(token.clone())
+            .add_step([Rule::new("hang", hang).set_globs(["*"])])
+            .build();
+        let cancel = async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            token.cancel();
+        };
+        let (res, _) = tokio::join!(build, cancel);
+        assert!(matches!(res, Err(Error::Cancelled)));
     }
 }